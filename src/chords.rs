@@ -0,0 +1,86 @@
+use crate::midi_translation::NOTE_NAMES;
+
+/*
+    Chord naming for notes played together, e.g. [60, 64, 67] => "C", [64, 67, 72] => "C/E".
+    Candidate roots are tried bass first, so ambiguous sets (C6/Am7) favour the played bass note.
+*/
+
+const TEMPLATES: [(&[u8], &str); 16] = [
+    (&[0, 4, 7], ""),
+    (&[0, 3, 7], "m"),
+    (&[0, 3, 6], "dim"),
+    (&[0, 4, 8], "aug"),
+    (&[0, 2, 7], "sus2"),
+    (&[0, 5, 7], "sus4"),
+    (&[0, 7], "5"),
+    (&[0, 4, 7, 10], "7"),
+    (&[0, 4, 7, 11], "maj7"),
+    (&[0, 3, 7, 10], "m7"),
+    (&[0, 3, 7, 11], "mMaj7"),
+    (&[0, 3, 6, 10], "m7b5"),
+    (&[0, 3, 6, 9], "dim7"),
+    (&[0, 4, 7, 9], "6"),
+    (&[0, 3, 7, 9], "m6"),
+    (&[0, 2, 4, 7], "add9"),
+];
+
+fn pitch_class_name(pitch_class: u8) -> String {
+    let name = NOTE_NAMES[pitch_class as usize % 12];
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+        None => String::new(),
+    }
+}
+
+pub fn chord_name(tones: &[u8]) -> Option<String> {
+    let bass = *tones.iter().min()?;
+
+    // Pitch classes in order of appearance above the bass
+    let mut pitch_classes: Vec<u8> = Vec::new();
+    let mut sorted = tones.to_vec();
+    sorted.sort();
+    for tone in sorted {
+        if !pitch_classes.contains(&(tone % 12)) {
+            pitch_classes.push(tone % 12);
+        }
+    }
+
+    if pitch_classes.len() < 2 {
+        return None;
+    }
+
+    for &root in &pitch_classes {
+        let mut intervals: Vec<u8> = pitch_classes.iter().map(|pc| (pc + 12 - root) % 12).collect();
+        intervals.sort();
+
+        if let Some((_, suffix)) = TEMPLATES.iter().find(|(template, _)| *template == intervals.as_slice()) {
+            let name = format!("{}{}", pitch_class_name(root), suffix);
+
+            return if root == bass % 12 {
+                Some(name)
+            } else {
+                Some(format!("{}/{}", name, pitch_class_name(bass % 12)))
+            };
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify() {
+        assert_eq!(chord_name(&[60, 64, 67]), Some("C".to_string()));
+        assert_eq!(chord_name(&[57, 60, 64]), Some("Am".to_string()));
+        assert_eq!(chord_name(&[64, 67, 72]), Some("C/E".to_string()));
+        assert_eq!(chord_name(&[61, 65, 68, 72]), Some("Dbmaj7".to_string()));
+        assert_eq!(chord_name(&[57, 60, 64, 67]), Some("Am7".to_string()));
+        assert_eq!(chord_name(&[60, 67, 72]), Some("C5".to_string()));
+        assert_eq!(chord_name(&[60, 72]), None);
+        assert_eq!(chord_name(&[60, 61, 62]), None);
+    }
+}
@@ -0,0 +1,144 @@
+use toml::map::Map;
+use toml::Value as TomlValue;
+
+pub const USAGE: &str = "Usage: jdw-keys-backend [OPTIONS]
+
+Options:
+  -c, --config <PATH>         Local config file (default: config.toml)
+      --instance <NAME>       Instance name, namespaces OSC addresses and external ids
+      --router-host <HOST>    OSC router host
+      --router-port <PORT>    OSC router port
+      --listen-port <PORT>    Port to receive subscribed OSC messages on
+      --bind-port <PORT>      Local port to send OSC from
+      --port-offset <N>       Added to the listen and bind ports not given on the command line
+      --midi-device <NAME>    MIDI input device (substring match on port name)
+      --session <PATH>        Session file to autosave history and state to
+      --restore               Restore history and state from the session file
+      --headless              Run without the terminal UI, logging to stderr
+      --list-devices          List MIDI input devices and exit
+  -h, --help                  Print this help and exit";
+
+pub struct CliArgs {
+    pub config_path: String,
+    pub headless: bool,
+    pub list_devices: bool,
+    pub help: bool,
+    overrides: Map<String, TomlValue>,
+}
+
+impl CliArgs {
+    // Command line values as a config layer, merged on top of the config files
+    pub fn config_overrides(&self) -> Option<TomlValue> {
+        if self.overrides.is_empty() {
+            None
+        } else {
+            Some(TomlValue::Table(self.overrides.clone()))
+        }
+    }
+}
+
+pub fn parse(args: impl Iterator<Item = String>) -> Result<CliArgs, String> {
+    let mut parsed = CliArgs {
+        config_path: "config.toml".to_string(),
+        headless: false,
+        list_devices: false,
+        help: false,
+        overrides: Map::new(),
+    };
+
+    let mut args = args.peekable();
+    while let Some(arg) = args.next() {
+        // Support both "--flag value" and "--flag=value"
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+            _ => (arg.clone(), None),
+        };
+
+        let mut value = || -> Result<String, String> {
+            inline_value
+                .clone()
+                .or_else(|| args.next())
+                .ok_or(format!("Missing value for {}", flag))
+        };
+
+        match flag.as_str() {
+            "-c" | "--config" => parsed.config_path = value()?,
+            "--instance" => {
+                parsed.overrides.insert("instance_name".to_string(), TomlValue::String(value()?));
+            }
+            "--router-host" => {
+                parsed.overrides.insert("router_host".to_string(), TomlValue::String(value()?));
+            }
+            "--router-port" => {
+                parsed.overrides.insert("router_port".to_string(), parse_port(&flag, &value()?)?);
+            }
+            "--listen-port" => {
+                parsed.overrides.insert("osc_listen_port".to_string(), parse_port(&flag, &value()?)?);
+            }
+            "--bind-port" => {
+                parsed.overrides.insert("local_bind_port".to_string(), parse_port(&flag, &value()?)?);
+            }
+            "--port-offset" => {
+                parsed.overrides.insert("instance_port_offset".to_string(), parse_port(&flag, &value()?)?);
+            }
+            "--midi-device" => {
+                parsed.overrides.insert("midi_device".to_string(), TomlValue::String(value()?));
+            }
+            "--session" => {
+                parsed.overrides.insert("session_file".to_string(), TomlValue::String(value()?));
+            }
+            "--restore" => {
+                parsed.overrides.insert("session_restore".to_string(), TomlValue::Boolean(true));
+            }
+            "--headless" => parsed.headless = true,
+            "--list-devices" => parsed.list_devices = true,
+            "-h" | "--help" => parsed.help = true,
+            _ => return Err(format!("Unknown argument: {}", arg)),
+        }
+    }
+
+    Ok(parsed)
+}
+
+fn parse_port(flag: &str, value: &str) -> Result<TomlValue, String> {
+    value
+        .parse::<u16>()
+        .map(|port| TomlValue::Integer(port as i64))
+        .map_err(|_| format!("Invalid port for {}: {}", flag, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> impl Iterator<Item = String> + '_ {
+        line.split_whitespace().map(String::from)
+    }
+
+    #[test]
+    fn verify() {
+        let parsed = parse(args("--headless --instance bass --listen-port=17778 -c other.toml --restore")).unwrap();
+        assert!(parsed.headless);
+        assert!(!parsed.list_devices);
+        assert_eq!(parsed.config_path, "other.toml");
+
+        let overrides = parsed.config_overrides().unwrap();
+        assert_eq!(overrides.get("instance_name").unwrap().as_str(), Some("bass"));
+        assert_eq!(overrides.get("osc_listen_port").unwrap().as_integer(), Some(17778));
+        assert_eq!(overrides.get("session_restore").unwrap().as_bool(), Some(true));
+
+        assert!(parse(args("")).unwrap().config_overrides().is_none());
+        assert!(parse(args("--router-port lots")).is_err());
+        assert!(parse(args("--instance")).is_err());
+        assert!(parse(args("--bogus")).is_err());
+
+        // The offset moves configured ports, never ones given on the command line
+        let offset = parse(args("--instance bass --port-offset 10 --listen-port 17778")).unwrap();
+        let cfg = crate::config::load(None, offset.config_overrides()).unwrap();
+        assert_eq!(cfg.osc_listen_port, 17778);
+        assert_eq!(cfg.local_bind_port, crate::config::Config::default().local_bind_port + 10);
+
+        let overflow = parse(args("--port-offset 65000")).unwrap();
+        assert!(crate::config::load(None, overflow.config_overrides()).is_err());
+    }
+}
@@ -1,5 +1,7 @@
+use bigdecimal::{BigDecimal, Zero};
 use serde::Deserialize;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::OnceLock;
 use toml::Value as TomlValue;
 
@@ -19,9 +21,65 @@ pub struct Config {
     pub message_args: Vec<String>,
     pub initial_mode: String,
     pub record_history: bool,
+    pub record_mode: String,
+    pub punch_out_loops: u32,
+    pub count_in_bars: u32,
     pub quantize_enabled: bool,
+    pub quantize_mode: String,
+    pub quantize_tuplet: u32,
+    pub quantize_swing: u8,
+    pub quantize_strength: u8,
     pub multiline_output: bool,
+    pub compress_repeats: bool,
     pub initial_octave: u8,
+    pub chord_window_ms: u64,
+    pub chord_annotations: bool,
+    pub sustain_mode: String,
+    pub loop_length: String,
+    pub loop_length_bars: u32,
+    pub lanes_per_instrument: bool,
+    pub section_headers: bool,
+    pub record_controls: bool,
+    pub control_output: String,
+    pub control_resolution: f64,
+    pub control_arg_names: Vec<String>,
+    pub midi_export_dir: String,
+    pub midi_export_quantized: bool,
+    pub midi_export_drum_base: u8,
+    pub session_file: String,
+    pub session_restore: bool,
+    pub session_autosave_secs: u64,
+    pub time_signature: String,
+    pub metronome_enabled: bool,
+    pub metronome_sound: String,
+    pub metronome_pack: String,
+    pub metronome_sample: i32,
+    pub metronome_accent_sample: i32,
+    pub metronome_synth: String,
+    pub metronome_note: u8,
+    pub metronome_accent_note: u8,
+    pub metronome_amp: f64,
+    pub metronome_accent_amp: f64,
+    pub output_lane: String,
+    pub sustain_grid_absolute: String,
+    pub sustain_grid_relative: String,
+    pub sustain_grid_legato: String,
+    pub sustain_legato_arg: String,
+    pub velocity_output: bool,
+    pub velocity_arg: String,
+    pub velocity_levels: u8,
+    pub velocity_default: u8,
+    pub velocity_min: f64,
+    pub velocity_max: f64,
+    pub instance_name: String,
+    pub instance_port_offset: u16,
+    pub copy_to_clipboard: bool,
+    pub output_sinks: Vec<String>,
+    pub output_file: String,
+    pub output_file_append: bool,
+    pub bbd_file: String,
+    pub bbd_marker: String,
+    pub midi_device: String,
     #[serde(default)]
     pub available_instruments: Vec<String>,
     #[serde(default)]
@@ -49,9 +107,65 @@ impl Default for Config {
             ],
             initial_mode: "keyboard".to_string(),
             record_history: true,
+            record_mode: "always".to_string(),
+            punch_out_loops: 0,
+            count_in_bars: 0,
             quantize_enabled: true,
+            quantize_mode: "relative".to_string(),
+            quantize_tuplet: 1,
+            quantize_swing: 50,
+            quantize_strength: 100,
             multiline_output: false,
+            compress_repeats: false,
             initial_octave: 5,
+            chord_window_ms: 40,
+            chord_annotations: false,
+            sustain_mode: "off".to_string(),
+            loop_length: "power_of_two".to_string(),
+            loop_length_bars: 4,
+            lanes_per_instrument: false,
+            section_headers: false,
+            record_controls: true,
+            control_output: "lanes".to_string(),
+            control_resolution: 0.01,
+            control_arg_names: Vec::new(),
+            midi_export_dir: ".".to_string(),
+            midi_export_quantized: true,
+            midi_export_drum_base: 36,
+            session_file: "session.toml".to_string(),
+            session_restore: false,
+            session_autosave_secs: 30,
+            time_signature: "4/4".to_string(),
+            metronome_enabled: false,
+            metronome_sound: "sample".to_string(),
+            metronome_pack: "EMU_EDrum".to_string(),
+            metronome_sample: 0,
+            metronome_accent_sample: 0,
+            metronome_synth: String::new(),
+            metronome_note: 84,
+            metronome_accent_note: 96,
+            metronome_amp: 0.2,
+            metronome_accent_amp: 0.4,
+            output_lane: "all".to_string(),
+            sustain_grid_absolute: "0.25".to_string(),
+            sustain_grid_relative: "0.25".to_string(),
+            sustain_grid_legato: "0.25".to_string(),
+            sustain_legato_arg: "legato".to_string(),
+            velocity_output: true,
+            velocity_arg: "amp".to_string(),
+            velocity_levels: 4,
+            velocity_default: 127,
+            velocity_min: 0.0,
+            velocity_max: 0.4,
+            instance_name: String::new(),
+            instance_port_offset: 0,
+            copy_to_clipboard: true,
+            output_sinks: vec!["clipboard".into()],
+            output_file: "history.txt".to_string(),
+            output_file_append: false,
+            bbd_file: String::new(),
+            bbd_marker: "keys".to_string(),
+            midi_device: "Arturia MiniLab mkII".to_string(),
             available_packs: vec![
                 "CR-78".into(),
                 "EMU_SP12".into(),
@@ -77,6 +191,24 @@ impl Config {
     pub fn get() -> &'static Config {
         CONFIG.get().expect("Config not initialized")
     }
+
+    // "/keyboard_octave" => "/bass/keyboard_octave" for instance "bass"
+    pub fn osc_address(&self, base: &str) -> String {
+        if self.instance_name.is_empty() {
+            base.to_string()
+        } else {
+            format!("/{}{}", self.instance_name, base)
+        }
+    }
+
+    // Prefix for synth/sample external ids, so instances don't modify each other's notes
+    pub fn external_id_prefix(&self) -> String {
+        if self.instance_name.is_empty() {
+            String::new()
+        } else {
+            format!("{}_", self.instance_name)
+        }
+    }
 }
 
 fn central_config_path() -> Option<String> {
@@ -125,6 +257,31 @@ fn merge_i64_into_u8(base: &mut u8, overlay: &TomlValue, key: &str) {
     }
 }
 
+fn merge_i64_into_i32(base: &mut i32, overlay: &TomlValue, key: &str) {
+    if let Some(v) = overlay.get(key).and_then(|v| v.as_integer()) {
+        *base = v as i32;
+    }
+}
+
+fn merge_i64_into_u32(base: &mut u32, overlay: &TomlValue, key: &str) {
+    if let Some(v) = overlay.get(key).and_then(|v| v.as_integer()) {
+        *base = v as u32;
+    }
+}
+
+fn merge_i64_into_u64(base: &mut u64, overlay: &TomlValue, key: &str) {
+    if let Some(v) = overlay.get(key).and_then(|v| v.as_integer()) {
+        *base = v.max(0) as u64;
+    }
+}
+
+fn merge_f64(base: &mut f64, overlay: &TomlValue, key: &str) {
+    // Accept integers too, so "1" works as well as "1.0"
+    if let Some(v) = overlay.get(key).and_then(|v| v.as_float().or(v.as_integer().map(|i| i as f64))) {
+        *base = v;
+    }
+}
+
 fn merge_bool(base: &mut bool, overlay: &TomlValue, key: &str) {
     if let Some(v) = overlay.get(key).and_then(|v| v.as_bool()) {
         *base = v;
@@ -149,14 +306,70 @@ fn merge_config(base: &mut Config, overlay: &TomlValue) {
     merge_string_vec(&mut base.message_args, overlay, "message_args");
     merge_str(&mut base.initial_mode, overlay, "initial_mode");
     merge_bool(&mut base.record_history, overlay, "record_history");
+    merge_str(&mut base.record_mode, overlay, "record_mode");
+    merge_i64_into_u32(&mut base.punch_out_loops, overlay, "punch_out_loops");
+    merge_i64_into_u32(&mut base.count_in_bars, overlay, "count_in_bars");
     merge_bool(&mut base.quantize_enabled, overlay, "quantize_enabled");
+    merge_str(&mut base.quantize_mode, overlay, "quantize_mode");
+    merge_i64_into_u32(&mut base.quantize_tuplet, overlay, "quantize_tuplet");
+    merge_i64_into_u8(&mut base.quantize_swing, overlay, "quantize_swing");
+    merge_i64_into_u8(&mut base.quantize_strength, overlay, "quantize_strength");
     merge_bool(&mut base.multiline_output, overlay, "multiline_output");
+    merge_bool(&mut base.compress_repeats, overlay, "compress_repeats");
     merge_i64_into_u8(&mut base.initial_octave, overlay, "initial_octave");
+    merge_i64_into_u64(&mut base.chord_window_ms, overlay, "chord_window_ms");
+    merge_bool(&mut base.chord_annotations, overlay, "chord_annotations");
+    merge_str(&mut base.sustain_mode, overlay, "sustain_mode");
+    merge_str(&mut base.loop_length, overlay, "loop_length");
+    merge_i64_into_u32(&mut base.loop_length_bars, overlay, "loop_length_bars");
+    merge_bool(&mut base.lanes_per_instrument, overlay, "lanes_per_instrument");
+    merge_bool(&mut base.section_headers, overlay, "section_headers");
+    merge_bool(&mut base.record_controls, overlay, "record_controls");
+    merge_str(&mut base.control_output, overlay, "control_output");
+    merge_f64(&mut base.control_resolution, overlay, "control_resolution");
+    merge_string_vec(&mut base.control_arg_names, overlay, "control_arg_names");
+    merge_str(&mut base.midi_export_dir, overlay, "midi_export_dir");
+    merge_bool(&mut base.midi_export_quantized, overlay, "midi_export_quantized");
+    merge_i64_into_u8(&mut base.midi_export_drum_base, overlay, "midi_export_drum_base");
+    merge_str(&mut base.session_file, overlay, "session_file");
+    merge_bool(&mut base.session_restore, overlay, "session_restore");
+    merge_i64_into_u64(&mut base.session_autosave_secs, overlay, "session_autosave_secs");
+    merge_str(&mut base.time_signature, overlay, "time_signature");
+    merge_bool(&mut base.metronome_enabled, overlay, "metronome_enabled");
+    merge_str(&mut base.metronome_sound, overlay, "metronome_sound");
+    merge_str(&mut base.metronome_pack, overlay, "metronome_pack");
+    merge_i64_into_i32(&mut base.metronome_sample, overlay, "metronome_sample");
+    merge_i64_into_i32(&mut base.metronome_accent_sample, overlay, "metronome_accent_sample");
+    merge_str(&mut base.metronome_synth, overlay, "metronome_synth");
+    merge_i64_into_u8(&mut base.metronome_note, overlay, "metronome_note");
+    merge_i64_into_u8(&mut base.metronome_accent_note, overlay, "metronome_accent_note");
+    merge_f64(&mut base.metronome_amp, overlay, "metronome_amp");
+    merge_f64(&mut base.metronome_accent_amp, overlay, "metronome_accent_amp");
+    merge_str(&mut base.output_lane, overlay, "output_lane");
+    merge_str(&mut base.sustain_grid_absolute, overlay, "sustain_grid_absolute");
+    merge_str(&mut base.sustain_grid_relative, overlay, "sustain_grid_relative");
+    merge_str(&mut base.sustain_grid_legato, overlay, "sustain_grid_legato");
+    merge_str(&mut base.sustain_legato_arg, overlay, "sustain_legato_arg");
+    merge_bool(&mut base.velocity_output, overlay, "velocity_output");
+    merge_str(&mut base.velocity_arg, overlay, "velocity_arg");
+    merge_i64_into_u8(&mut base.velocity_levels, overlay, "velocity_levels");
+    merge_i64_into_u8(&mut base.velocity_default, overlay, "velocity_default");
+    merge_f64(&mut base.velocity_min, overlay, "velocity_min");
+    merge_f64(&mut base.velocity_max, overlay, "velocity_max");
+    merge_str(&mut base.instance_name, overlay, "instance_name");
+    merge_u16(&mut base.instance_port_offset, overlay, "instance_port_offset");
+    merge_bool(&mut base.copy_to_clipboard, overlay, "copy_to_clipboard");
+    merge_string_vec(&mut base.output_sinks, overlay, "output_sinks");
+    merge_str(&mut base.output_file, overlay, "output_file");
+    merge_bool(&mut base.output_file_append, overlay, "output_file_append");
+    merge_str(&mut base.bbd_file, overlay, "bbd_file");
+    merge_str(&mut base.bbd_marker, overlay, "bbd_marker");
+    merge_str(&mut base.midi_device, overlay, "midi_device");
     merge_string_vec(&mut base.available_packs, overlay, "available_packs");
     merge_string_vec(&mut base.available_instruments, overlay, "available_instruments");
 }
 
-pub fn load(config_path: Option<&str>) -> Config {
+pub fn load(config_path: Option<&str>, overrides: Option<TomlValue>) -> Result<Config, String> {
     let mut cfg = Config::default();
 
     if let Some(central) = load_central_section() {
@@ -173,10 +386,47 @@ pub fn load(config_path: Option<&str>) -> Config {
         }
     }
 
-    cfg
+    // Ports given on the command line are used as they are
+    let is_override = |key: &str| overrides.as_ref().is_some_and(|overrides| overrides.get(key).is_some());
+    let offset_listen_port = !is_override("osc_listen_port");
+    let offset_bind_port = !is_override("local_bind_port");
+
+    if let Some(overrides) = overrides {
+        merge_config(&mut cfg, &overrides);
+    }
+
+    // Grids are rounded to and divided by, so each must be a positive number of beats
+    let defaults = Config::default();
+    for (value, default, key) in [
+        (&mut cfg.quantization, defaults.quantization, "quantization"),
+        (&mut cfg.sustain_grid_absolute, defaults.sustain_grid_absolute, "sustain_grid_absolute"),
+        (&mut cfg.sustain_grid_relative, defaults.sustain_grid_relative, "sustain_grid_relative"),
+        (&mut cfg.sustain_grid_legato, defaults.sustain_grid_legato, "sustain_grid_legato"),
+    ] {
+        if !BigDecimal::from_str(value).is_ok_and(|grid| grid > BigDecimal::zero()) {
+            eprintln!("Warning: {} '{}' is not a positive number of beats. Using {}.", key, value, default);
+            *value = default;
+        }
+    }
+
+    // Several instances can share one router by offsetting their own ports
+    let offset = cfg.instance_port_offset;
+    let offset_port = |port: u16, key: &str| {
+        let error = format!("instance_port_offset {} puts {} {} past the highest port", offset, key, port);
+        port.checked_add(offset).ok_or(error)
+    };
+    if offset_listen_port {
+        cfg.osc_listen_port = offset_port(cfg.osc_listen_port, "osc_listen_port")?;
+    }
+    if offset_bind_port {
+        cfg.local_bind_port = offset_port(cfg.local_bind_port, "local_bind_port")?;
+    }
+
+    Ok(cfg)
 }
 
-pub fn init(config_path: Option<&str>) {
-    let config = load(config_path);
+pub fn init(config_path: Option<&str>, overrides: Option<TomlValue>) -> Result<(), String> {
+    let config = load(config_path, overrides)?;
     CONFIG.set(config).ok();
+    Ok(())
 }
@@ -1,29 +1,371 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::str::FromStr;
 use std::time::{Duration, Instant};
 
-use bigdecimal::{BigDecimal, Zero};
+use bigdecimal::{BigDecimal, FromPrimitive, One, RoundingMode, Zero};
 use itertools::Itertools;
-use rand::distributions::Alphanumeric;
-use rand::seq::IteratorRandom;
-use rand::{thread_rng, Rng};
 use rosc::OscType;
 
-use crate::event_model::{BeatBreak, Event, NoteOff, NoteOn, Silence};
+use crate::chords;
+use crate::config::Config;
+use crate::event_model::{BeatBreak, Control, Event, NoteOn, Silence};
+use crate::midi_translation;
+use crate::output;
+use crate::patterns;
+use crate::patterns::Token;
+use crate::quantization::Quantization;
+use crate::time_signature::TimeSignature;
 use crate::util;
 use crate::util::duration_to_beats;
 
 const SILENCE_REP: &str = "x";
 const BEAT_BREAK_REP: &str = ".";
 
-pub fn stringify_history(sequence: Vec<SequentialEvent>, ends_on_sample: bool, multiline: bool) -> String {
+#[derive(Clone)]
+pub struct StringifyOptions {
+    pub ends_on_sample: bool,
+    pub multiline: bool,
+    pub velocity: Option<VelocityOutput>,
+    pub sustain: SustainOutput,
+    // Prefix the notation with a comment line naming the played chords
+    pub chord_annotations: bool,
+    // Decides "len", with the difference to the played time padded as trailing silence
+    pub loop_length: LoopLength,
+    pub time_signature: TimeSignature,
+    // Write bars played more than once as "$a = ..." definitions, also when not multiline
+    pub compress_repeats: bool,
+    /*
+        Starts every definition name, e.g. "keys_" to keep them apart from other names in a shared file.
+        Each sequence of several adds its own, e.g. "keys_pads_" or "keys_synth2_".
+    */
+    pub pattern_prefix: String,
+    // Header every sequence with its lane and sound, even when there is only one
+    pub section_headers: bool,
+    pub controls: ControlOutput,
+}
+
+impl StringifyOptions {
+    // Output as configured; State::render_settings replaces what can be changed while running
+    pub fn from_config(cfg: &Config) -> StringifyOptions {
+        StringifyOptions {
+            // Whether the last note is a pad hit; stringify_lanes sets it for each sequence it writes
+            ends_on_sample: false,
+            multiline: cfg.multiline_output,
+            velocity: VelocityOutput::from_config(cfg),
+            sustain: SustainOutput::from_config(cfg, SustainMode::from_name(&cfg.sustain_mode).unwrap_or(SustainMode::Off)),
+            chord_annotations: cfg.chord_annotations,
+            loop_length: LoopLength::from_name(&cfg.loop_length, cfg.loop_length_bars).unwrap_or(LoopLength::PowerOfTwoBars),
+            time_signature: TimeSignature::from_name(&cfg.time_signature).unwrap_or(TimeSignature { beats: 4, unit: 4 }),
+            compress_repeats: cfg.compress_repeats,
+            pattern_prefix: output::pattern_namespace(cfg),
+            section_headers: cfg.section_headers,
+            controls: ControlOutput::from_config(cfg),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum QuantizeMode {
+    // Round the time between consecutive onsets; rounding errors add up over long takes
+    Relative,
+    // Snap each onset to the grid counted from the last beat break (loop start)
+    Grid,
+}
+
+impl QuantizeMode {
+    pub fn from_name(name: &str) -> Option<QuantizeMode> {
+        match name.to_lowercase().as_str() {
+            "relative" => Some(QuantizeMode::Relative),
+            "grid" => Some(QuantizeMode::Grid),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            QuantizeMode::Relative => "relative",
+            QuantizeMode::Grid => "grid",
+        }
+    }
+
+    pub fn next(&self) -> QuantizeMode {
+        match self {
+            QuantizeMode::Relative => QuantizeMode::Grid,
+            QuantizeMode::Grid => QuantizeMode::Relative,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SustainMode {
+    Off,
+    // Held time in beats: ",sus0.75"
+    Absolute,
+    // Sustain follows the reserved time of each note: ",sus*0.5"
+    Relative,
+    // Held time as a fraction of the reserved time: ",legato0.5"
+    Legato,
+}
+
+impl SustainMode {
+    pub fn from_name(name: &str) -> Option<SustainMode> {
+        match name.to_lowercase().as_str() {
+            "off" => Some(SustainMode::Off),
+            "absolute" => Some(SustainMode::Absolute),
+            "relative" => Some(SustainMode::Relative),
+            "legato" => Some(SustainMode::Legato),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SustainMode::Off => "off",
+            SustainMode::Absolute => "absolute",
+            SustainMode::Relative => "relative",
+            SustainMode::Legato => "legato",
+        }
+    }
+
+    pub fn next(&self) -> SustainMode {
+        match self {
+            SustainMode::Off => SustainMode::Absolute,
+            SustainMode::Absolute => SustainMode::Relative,
+            SustainMode::Relative => SustainMode::Legato,
+            SustainMode::Legato => SustainMode::Off,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LoopLength {
+    // Rounded up to whole bars
+    NextBar,
+    // 1, 2, 4, 8 ... bars
+    PowerOfTwoBars,
+    // As played, without padding
+    Exact,
+    // This many bars, or a multiple of it for longer takes
+    Bars(u32),
+}
+
+impl LoopLength {
+    // <bars> is only used by "bars"
+    pub fn from_name(name: &str, bars: u32) -> Option<LoopLength> {
+        match name.to_lowercase().as_str() {
+            "next_bar" => Some(LoopLength::NextBar),
+            "power_of_two" => Some(LoopLength::PowerOfTwoBars),
+            "exact" => Some(LoopLength::Exact),
+            "bars" if bars > 0 => Some(LoopLength::Bars(bars)),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LoopLength::NextBar => "next_bar",
+            LoopLength::PowerOfTwoBars => "power_of_two",
+            LoopLength::Exact => "exact",
+            LoopLength::Bars(_) => "bars",
+        }
+    }
+
+    // Loop length in beats for <total> played beats; always at least one bar, except when exact
+    pub fn length(&self, total: &BigDecimal, time_signature: &TimeSignature) -> BigDecimal {
+        let bar = time_signature.bar_length();
+        let bars = (total / bar.clone()).with_scale_round(0, RoundingMode::Up).max(BigDecimal::one());
+
+        let length = match self {
+            LoopLength::NextBar => bars * bar,
+            LoopLength::PowerOfTwoBars => util::next_power_of_two(bars) * bar,
+            LoopLength::Exact => total.clone(),
+            LoopLength::Bars(fixed) => {
+                let fixed = BigDecimal::from(*fixed);
+                let repeats = (bars / fixed.clone()).with_scale_round(0, RoundingMode::Up);
+                repeats * fixed * bar
+            }
+        };
+
+        length.normalized()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ControlMode {
+    Off,
+    // One sequence of values per control bus, next to the note lanes
+    Lanes,
+    // Value of each named bus at the onset of every note: ",cutoff0.4"
+    Args,
+}
+
+impl ControlMode {
+    pub fn from_name(name: &str) -> Option<ControlMode> {
+        match name.to_lowercase().as_str() {
+            "off" => Some(ControlMode::Off),
+            "lanes" => Some(ControlMode::Lanes),
+            "args" => Some(ControlMode::Args),
+            _ => None,
+        }
+    }
+}
+
+// How recorded knob moves are written
+#[derive(Clone)]
+pub struct ControlOutput {
+    pub mode: ControlMode,
+    // Values are rounded to multiples of this
+    pub resolution: BigDecimal,
+    // Arg name per control bus, for args mode; buses without a name are left out
+    pub arg_names: Vec<String>,
+}
+
+impl ControlOutput {
+    pub fn from_config(cfg: &Config) -> ControlOutput {
+        ControlOutput {
+            mode: ControlMode::from_name(&cfg.control_output).unwrap_or(ControlMode::Lanes),
+            // Via the shortest decimal form, as from_f64 keeps the binary error: 0.05 is 0.05000000000000000277...
+            resolution: BigDecimal::from_str(&cfg.control_resolution.to_string())
+                .ok()
+                .filter(|resolution| *resolution > BigDecimal::zero())
+                .unwrap_or(BigDecimal::from_str("0.01").unwrap()),
+            arg_names: cfg.control_arg_names.clone(),
+        }
+    }
+
+    // E.g. ",cutoff0.4,res0.2" for the named buses in <controls>
+    fn format(&self, controls: &[(i32, f32)]) -> String {
+        if self.mode != ControlMode::Args {
+            return String::new();
+        }
+
+        controls
+            .iter()
+            .filter_map(|(bus, value)| {
+                let name = self.arg_names.get(usize::try_from(*bus).ok()?).filter(|name| !name.is_empty())?;
+                let value = BigDecimal::from_f32(*value)?;
+                Some(format!(",{}{}", name, util::round_to_nearest(value, self.resolution.clone()).normalized()))
+            })
+            .join("")
+    }
+}
+
+// How held note time is written as a per-note arg; each mode rounds to its own grid
+#[derive(Clone)]
+pub struct SustainOutput {
+    pub mode: SustainMode,
+    pub absolute_grid: BigDecimal,
+    pub relative_grid: BigDecimal,
+    pub legato_grid: BigDecimal,
+    pub legato_arg: String,
+}
+
+impl SustainOutput {
+    pub fn from_config(cfg: &Config, mode: SustainMode) -> SustainOutput {
+        SustainOutput {
+            mode,
+            // Grids are checked to be positive by config::load
+            absolute_grid: BigDecimal::from_str(&cfg.sustain_grid_absolute).unwrap(),
+            relative_grid: BigDecimal::from_str(&cfg.sustain_grid_relative).unwrap(),
+            legato_grid: BigDecimal::from_str(&cfg.sustain_grid_legato).unwrap(),
+            legato_arg: cfg.sustain_legato_arg.clone(),
+        }
+    }
+
+    /*
+        <sustain> is the held time in beats (None for notes without release, e.g. samples),
+            <reserved> the time until the next note, including any final padding.
+    */
+    fn format(&self, sustain: Option<&BigDecimal>, reserved: &BigDecimal, ends_on_sample: bool) -> Option<String> {
+        match self.mode {
+            SustainMode::Off => None,
+            SustainMode::Absolute => sustain.map(|sus| {
+                // Never round a played note down to nothing
+                let rounded = util::round_to_nearest(sus.clone(), self.absolute_grid.clone())
+                    .max(self.absolute_grid.clone());
+                format!(",sus{}", rounded.normalized())
+            }),
+            // Referential arg support in shuttle means this can be set as a default to minimize clutter
+            SustainMode::Relative if !ends_on_sample => {
+                let rounded = util::round_to_nearest(reserved.clone(), self.relative_grid.clone());
+                Some(format!(",sus*{}", rounded.normalized()))
+            }
+            SustainMode::Relative => None,
+            SustainMode::Legato => sustain
+                .filter(|_| !reserved.is_zero())
+                .map(|sus| {
+                    let ratio = util::round_to_nearest(sus.clone() / reserved.clone(), self.legato_grid.clone())
+                        .max(self.legato_grid.clone());
+                    format!(",{}{}", self.legato_arg, ratio.normalized())
+                }),
+        }
+    }
+}
+
+// How note velocity is written as a per-note arg
+#[derive(Clone)]
+pub struct VelocityOutput {
+    pub arg: String,
+    // Quantize velocity to this many steps (0 = as played)
+    pub levels: u8,
+    // Velocity that needs no arg, since it matches what the synth args already say
+    pub default: u8,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl VelocityOutput {
+    pub fn from_config(cfg: &Config) -> Option<VelocityOutput> {
+        if !cfg.velocity_output {
+            return None;
+        }
+
+        Some(VelocityOutput {
+            arg: cfg.velocity_arg.clone(),
+            levels: cfg.velocity_levels,
+            default: cfg.velocity_default,
+            min: cfg.velocity_min,
+            max: cfg.velocity_max,
+        })
+    }
+
+    fn quantize(&self, velocity: u8) -> u8 {
+        if self.levels == 0 {
+            return velocity;
+        }
+
+        let step = 127.0 / self.levels as f64;
+        let level = (velocity as f64 / step).round().max(1.0);
+        (level * step).round().min(127.0) as u8
+    }
+
+    // E.g. ",amp0.3", or None when the velocity is the default one
+    fn format(&self, velocity: u8) -> Option<String> {
+        let quantized = self.quantize(velocity);
+
+        if quantized == self.quantize(self.default) {
+            return None;
+        }
+
+        let value = self.min + (self.max - self.min) * (quantized as f64 / 127.0);
+        let rounded = BigDecimal::from_f64(value).unwrap_or(BigDecimal::zero()).round(2);
+
+        Some(format!(",{}{}", self.arg, rounded.normalized()))
+    }
+}
+
+pub fn stringify_history(sequence: Vec<SequentialEvent>, options: &StringifyOptions) -> String {
+    let ends_on_sample = options.ends_on_sample;
+    let multiline = options.multiline;
+
     let total_beats = sequence
         .iter()
         .map(|event| event.reserved_beats.clone())
         .reduce(|a, b| a + b)
         .unwrap_or(BigDecimal::zero());
 
-    let desired_total =
-        util::next_power_of_two(total_beats.clone()).max(BigDecimal::from_str("4.0").unwrap());
+    let desired_total = options.loop_length.length(&total_beats, &options.time_signature);
 
     let difference = desired_total.clone() - total_beats.clone();
 
@@ -32,7 +374,7 @@ pub fn stringify_history(sequence: Vec<SequentialEvent>, ends_on_sample: bool, m
 
     // Somewhat roundabout looping to easily access "is last element" for the final padding silence
     let mut iterator = sequence.iter().peekable();
-    let mut raw_notes: Vec<String> = Vec::new();
+    let mut raw_notes: Vec<Token> = Vec::new();
     while let Some(note) = iterator.next() {
         let bonus = if iterator.peek().is_none() {
             difference.normalized()
@@ -55,70 +397,375 @@ pub fn stringify_history(sequence: Vec<SequentialEvent>, ends_on_sample: bool, m
             }
         };
 
-        if let Some(sus) = &note.sustain_beats {
-            let rounded = sus.round(2);
-            // TODO: Accurate sustain, but becomes hard to read
-            // base += format!(",sus{:.4}", rounded.normalized()).as_str();
+        if let (Some(velocity), Some(output)) = (note.velocity, &options.velocity) {
+            if let Some(arg) = output.format(velocity) {
+                base += arg.as_str();
+            }
         }
 
-        // Experimental time-relative sus arg for sequences that end with notes
-        if !ends_on_sample
-            && note.representation != BEAT_BREAK_REP
-            && note.representation != SILENCE_REP
-        {
-            // TODO: Removing this too for now
-            // Referential arg support in shuttle means we can set this as a default and minimize clutter
-            //base += format!(",sus*{}", note.reserved_beats.normalized() + bonus).as_str();
+        let is_note = note.representation != BEAT_BREAK_REP && note.representation != SILENCE_REP;
+
+        if is_note {
+            if let Some(arg) = options.sustain.format(note.sustain_beats.as_ref(), &full_beats, ends_on_sample) {
+                base += arg.as_str();
+            }
+
+            base += options.controls.format(&note.controls).as_str();
         }
 
         // Extra guard to avoid zero-length silences (see above for how this is avoided with breaks)
         if !(full_beats == BigDecimal::zero() && note.representation == SILENCE_REP) {
-            raw_notes.push(base);
+            raw_notes.push((base, full_beats));
         }
     }
 
-    let notes = raw_notes.join(" ");
+    let notes = raw_notes.iter().map(|(note, _)| note).join(" ");
 
-    if (multiline) {
-        let mut vars: Vec<String> = vec![];
+    let chord_names: Vec<&str> = sequence.iter().filter_map(|note| note.chord.as_deref()).collect();
+    let annotation = if options.chord_annotations && !chord_names.is_empty() {
+        format!("# chords: {}\n", chord_names.join(" "))
+    } else {
+        String::new()
+    };
 
-        let lines = notes
-            .split(" . ")
-            .enumerate()
-            .map(|(_, part)| {
-                let id = generate_random_string();
-                vars.push(format!("${}", id));
-                format!("${} = {}", id, part)
-            })
-            .collect::<Vec<String>>()
-            .join("\n");
+    let patterns = patterns::find(&raw_notes, &options.time_signature.bar_length(), &options.pattern_prefix);
+    let definitions = patterns
+        .definitions
+        .iter()
+        .map(|(name, body)| format!("{} = {}\n", name, body))
+        .join("");
+    let references = patterns.references.join(" ");
 
-        format!("{}\n{}", lines, vars.join(" "))
+    if (multiline) {
+        format!("{}{}{}", annotation, definitions, references)
+    } else if options.compress_repeats && patterns.has_repeats() {
+        format!("{}{}({}):len{},tot{}", annotation, definitions, references, desired_total, total_beats)
     } else {
-        format!("({}):len{},tot{}", notes, desired_total, total_beats)
+        format!("{}({}):len{},tot{}", annotation, notes, desired_total, total_beats)
     }
 }
 
-fn generate_random_string() -> String {
-    let mut rng = thread_rng();
-    (0..6) // Generate 6 characters
-        .map(|_| {
-            "abcdefghijklmnopqrstuvxyz_"
-                .chars()
-                .choose(&mut rng)
-                .unwrap()
-        }) // Choose random characters from Alphanumeric
-        .collect::<String>() // Collect the characters into a String
+/*
+    Stringify the selected lane, or all lanes one after the other with a "# <lane>" header line each.
+    With control lanes on, all lanes are followed by one "# bus <n>" sequence of values per moved control bus.
+    A lane that switched instrument, pack or args is written as one sequence per section, with the sound
+        in the header: "# synth: superpiano amp0.5". Each sequence gets its own len/tot; ends_on_sample
+        in <options> is replaced by that of each sequence.
+*/
+pub fn stringify_lanes(
+    history: &EventHistory,
+    selection: &str,
+    per_instrument: bool,
+    sequence_options: &SequenceOptions,
+    options: &StringifyOptions,
+) -> String {
+    let lanes = history.lanes(per_instrument);
+
+    let mut selected: Vec<&Lane> = lanes
+        .iter()
+        .filter(|lane| selection == ALL_LANES || lane.name() == selection)
+        .collect();
+
+    // The selected lane may be gone, e.g. after an undo
+    if selected.is_empty() {
+        selected = lanes.iter().collect();
+    }
+
+    let all_sections = history.sections(per_instrument);
+    // <section, its position within the lane, sections in the lane>
+    let sections: Vec<(&Section, usize, usize)> = selected
+        .iter()
+        .flat_map(|lane| {
+            let lane_sections: Vec<&Section> = all_sections.iter().filter(|section| section.lane == **lane).collect();
+            let count = lane_sections.len();
+            lane_sections.into_iter().enumerate().map(move |(position, section)| (section, position, count))
+        })
+        .collect();
+
+    let mut sequences: Vec<String> = sections
+        .iter()
+        .map(|(section, position, lane_sections)| {
+            // History with a single section is that section
+            let (ends_on_sample, sequence) = if all_sections.len() == 1 {
+                (history.ends_on_sample(), history.as_sequence(sequence_options))
+            } else {
+                (
+                    history.section_ends_on_sample(section, per_instrument),
+                    history.section_sequence(section, per_instrument, sequence_options),
+                )
+            };
+            let pattern_prefix = match (sections.len(), lane_sections) {
+                (1, _) => options.pattern_prefix.clone(),
+                (_, 1) => format!("{}{}_", options.pattern_prefix, section.lane.name()),
+                _ => format!("{}{}{}_", options.pattern_prefix, section.lane.name(), position + 1),
+            };
+            let section_options = StringifyOptions { ends_on_sample, pattern_prefix, ..options.clone() };
+            let stringified = stringify_history(sequence, &section_options);
+
+            if sections.len() > 1 || options.section_headers {
+                format!("{}\n{}", section.header(options.section_headers || *lane_sections > 1), stringified)
+            } else {
+                stringified
+            }
+        })
+        .collect();
+
+    // Control lanes go with everything, not with a single selected lane
+    if options.controls.mode == ControlMode::Lanes && selection == ALL_LANES {
+        let control_options = StringifyOptions {
+            ends_on_sample: false,
+            velocity: None,
+            sustain: SustainOutput { mode: SustainMode::Off, ..options.sustain.clone() },
+            chord_annotations: false,
+            controls: ControlOutput { mode: ControlMode::Off, ..options.controls.clone() },
+            ..options.clone()
+        };
+
+        for bus in history.control_buses() {
+            let sequence = history.control_sequence(bus, &options.controls.resolution, sequence_options);
+            let bus_options = StringifyOptions {
+                pattern_prefix: format!("{}bus{}_", options.pattern_prefix, bus),
+                ..control_options.clone()
+            };
+            sequences.push(format!("# bus {}\n{}", bus, stringify_history(sequence, &bus_options)));
+        }
+    }
+
+    sequences.join("\n")
 }
 
+#[derive(Clone)]
 pub struct SequentialEvent {
     representation: String,
     reserved_beats: BigDecimal,
     sustain_beats: Option<BigDecimal>,
+    velocity: Option<u8>,
+    chord: Option<String>,
+    // <bus, value> of the control buses at the onset
+    controls: Vec<(i32, f32)>,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct SequenceOptions {
+    pub bpm: i64,
+    pub quantization: Quantization,
+    pub quantize_mode: QuantizeMode,
+    // Notes starting this close together share one onset, like a chord (zero keeps onsets as played)
+    pub chord_window: Duration,
+}
+
+impl SequenceOptions {
+    // Sequencing as configured; State::render_settings replaces what can be changed while running
+    pub fn from_config(cfg: &Config) -> SequenceOptions {
+        SequenceOptions {
+            bpm: cfg.bpm,
+            quantization: Quantization::from_config(cfg),
+            quantize_mode: QuantizeMode::from_name(&cfg.quantize_mode).unwrap_or(QuantizeMode::Relative),
+            chord_window: Duration::from_millis(cfg.chord_window_ms),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum UndoScope {
+    // The last played note
+    Note,
+    // Everything since the last beat break
+    Take,
+    // Everything
+    All,
+}
+
+impl UndoScope {
+    pub fn from_name(name: &str) -> Option<UndoScope> {
+        match name.to_lowercase().as_str() {
+            "note" => Some(UndoScope::Note),
+            "take" => Some(UndoScope::Take),
+            "all" | "clear" => Some(UndoScope::All),
+            _ => None,
+        }
+    }
+}
+
+pub const ALL_LANES: &str = "all";
+
+// Part of history that is stringified on its own
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Lane {
+    Synth,
+    Pads,
+    // Synth notes of a single instrument, when splitting lanes per instrument
+    Instrument(String),
+}
+
+impl Lane {
+    fn of(note_on: &NoteOn, per_instrument: bool) -> Lane {
+        Lane::new(note_on.is_sample, &note_on.instrument, per_instrument)
+    }
+
+    fn new(is_sample: bool, instrument: &str, per_instrument: bool) -> Lane {
+        if is_sample {
+            Lane::Pads
+        } else if per_instrument {
+            Lane::Instrument(instrument.to_string())
+        } else {
+            Lane::Synth
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Lane::Synth => "synth".to_string(),
+            Lane::Pads => "pads".to_string(),
+            Lane::Instrument(name) => name.to_string(),
+        }
+    }
+}
+
+/*
+    Notes of a lane played with the same instrument (pack for pads) and args.
+    Notation has no way to switch sound mid-sequence, so each section is written on its own.
+*/
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Section {
+    pub lane: Lane,
+    pub instrument: String,
+    // In notation form, e.g. "amp0.5,ofs0"
+    pub args: String,
+}
+
+impl Section {
+    fn of(note_on: &NoteOn, per_instrument: bool) -> Section {
+        Section {
+            lane: Lane::of(note_on, per_instrument),
+            instrument: note_on.instrument.clone(),
+            args: util::shuttlefiy_args(note_on.args.clone()),
+        }
+    }
+
+    // "# synth: superpiano amp0.5", or "# synth" without the sound
+    fn header(&self, with_sound: bool) -> String {
+        if !with_sound {
+            return format!("# {}", self.lane.name());
+        }
+
+        format!("# {}: {} {}", self.lane.name(), self.instrument, self.args).trim_end().to_string()
+    }
+}
+
+// Loop of recorded events: from a beat break (or the start of history) up to the next one
+#[derive(Clone, PartialEq, Debug)]
+pub struct Take {
+    start: usize,
+    end: usize,
+    pub notes: usize,
+}
+
+// Max amount of undo steps that can be redone
+const MAX_REDO: usize = 50;
+
+// Event played at a point in time, with what is needed to sequence it
+struct TimelineEntry<'a> {
+    event: &'a Event,
+    sustain: Option<Duration>,
+    // <bus, value> of every control bus moved so far
+    controls: Vec<(i32, f32)>,
+    start: Instant,
+    chord: Option<String>,
+}
+
+// <event, sustain, control values> as handed to timeline()
+type TimelineInput<'a> = (&'a Event, Option<Duration>, Vec<(i32, f32)>);
+
+// Knob moves on the same bus closer together than this are recorded as one
+const CONTROL_THINNING: Duration = Duration::from_millis(20);
+
+/*
+    Sequences of finished takes, per section (None for all of history), keyed by the index the take starts at.
+    A finished take only changes if a note in it is released late, or if the options change.
+*/
+#[derive(Default)]
+struct SequenceCache {
+    options: Option<SequenceOptions>,
+    // <(section, take start), (take end, sequence)>
+    takes: HashMap<(Option<Section>, usize), (usize, Vec<SequentialEvent>)>,
+}
+
+// Instrument (pack for pads) and args notes were played with, with where they were last played
+struct Sound {
+    is_sample: bool,
+    instrument: String,
+    // In notation form, as in Section
+    args: String,
+    // Index of the last NoteOn played with it, and of the last NoteOff releasing one
+    last_on: usize,
+    last_off: Option<usize>,
+}
+
+impl Sound {
+    fn section(&self, per_instrument: bool) -> Section {
+        Section {
+            lane: Lane::new(self.is_sample, &self.instrument, per_instrument),
+            instrument: self.instrument.clone(),
+            args: self.args.clone(),
+        }
+    }
+}
+
+/*
+    What is needed of history as a whole to sequence and stringify it, kept up to date as events are pushed,
+        so rendering doesn't take a pass over all events for each of it.
+*/
+#[derive(Default)]
+struct HistoryIndex {
+    // Index of the first event that isn't a release
+    first: Option<usize>,
+    // <index, values of the control buses moved before it> of each beat break
+    breaks: Vec<(usize, Vec<(i32, f32)>)>,
+    // Index of the last beat break or silence
+    last_rest: Option<usize>,
+    // <bus, value> of every control bus moved so far
+    controls: Vec<(i32, f32)>,
+    // <bus, indices of its moves>, in order of first move
+    buses: Vec<(i32, Vec<usize>)>,
+    // In order of first appearance
+    sounds: Vec<Sound>,
+}
+
+impl HistoryIndex {
+    fn sound_of(&mut self, note_on: &NoteOn, index: usize) -> &mut Sound {
+        let args = util::shuttlefiy_args(note_on.args.clone());
+
+        let position = self
+            .sounds
+            .iter()
+            .position(|sound| sound.is_sample == note_on.is_sample && sound.instrument == note_on.instrument && sound.args == args);
+
+        let position = position.unwrap_or_else(|| {
+            self.sounds.push(Sound {
+                is_sample: note_on.is_sample,
+                instrument: note_on.instrument.clone(),
+                args,
+                last_on: index,
+                last_off: None,
+            });
+            self.sounds.len() - 1
+        });
+
+        &mut self.sounds[position]
+    }
 }
 
 pub struct EventHistory {
     events: Vec<Event>,
+    // Per event: for a NoteOn the index of its release, for a NoteOff the first NoteOn it released
+    links: Vec<Option<usize>>,
+    // Indices of NoteOns not released yet, by note id
+    open: HashMap<String, Vec<usize>>,
+    // Event lists as they were before each undo, most recent last
+    redo_stack: Vec<Vec<Event>>,
+    index: HistoryIndex,
+    sequence_cache: RefCell<SequenceCache>,
     pub modified: bool,
 }
 
@@ -126,45 +773,157 @@ impl EventHistory {
     pub fn new() -> EventHistory {
         EventHistory {
             events: Vec::new(),
+            links: Vec::new(),
+            open: HashMap::new(),
+            redo_stack: Vec::new(),
+            index: HistoryIndex::default(),
+            sequence_cache: RefCell::new(SequenceCache::default()),
             modified: false,
         }
     }
 
+    fn from_events(events: Vec<Event>) -> EventHistory {
+        let mut history = EventHistory::new();
+        history.replace_events(events);
+        history
+    }
+
     pub fn register_beatbreak(&mut self, time: Instant) {
         let event = if self.is_silent() {
-            self.events.clear();
+            self.replace_events(Vec::new());
             Event::Silence(Silence { time })
         } else {
             Event::BeatBreak(BeatBreak { time })
         };
 
-        self.events.push(event);
+        self.record(event);
+    }
+
+    // Append a newly played event; anything new invalidates what was undone
+    fn record(&mut self, event: Event) {
+        self.push(event);
+        self.redo_stack.clear();
         self.modified = true;
     }
 
+    // Append an event, linking a release to the presses it ends
+    fn push(&mut self, event: Event) {
+        let index = self.events.len();
+
+        if self.index.first.is_none() && !matches!(event, Event::NoteOff(_)) {
+            self.index.first = Some(index);
+        }
+
+        let link = match &event {
+            Event::NoteOn(note_on) => {
+                self.open.entry(note_on.id.clone()).or_default().push(index);
+                self.index.sound_of(note_on, index).last_on = index;
+                None
+            }
+            Event::NoteOff(note_off) => {
+                let released = self.open.remove(&note_off.id).unwrap_or_default();
+
+                for on_index in &released {
+                    self.links[*on_index] = Some(index);
+                    if let Event::NoteOn(note_on) = &self.events[*on_index] {
+                        self.index.sound_of(note_on, *on_index).last_off = Some(index);
+                    }
+
+                    // The take holding the note now has a different sustain
+                    self.sequence_cache
+                        .get_mut()
+                        .takes
+                        .retain(|(_, start), (end, _)| !(*start <= *on_index && *on_index < *end));
+                }
+
+                released.first().copied()
+            }
+            Event::BeatBreak(_) => {
+                self.index.breaks.push((index, self.index.controls.clone()));
+                self.index.last_rest = Some(index);
+                None
+            }
+            Event::Silence(_) => {
+                self.index.last_rest = Some(index);
+                None
+            }
+            Event::Control(control) => {
+                set_control(&mut self.index.controls, control);
+                match self.index.buses.iter_mut().find(|(bus, _)| *bus == control.bus) {
+                    Some((_, moves)) => moves.push(index),
+                    None => self.index.buses.push((control.bus, vec![index])),
+                }
+                None
+            }
+        };
+
+        self.events.push(event);
+        self.links.push(link);
+    }
+
+    // Replace all events, rebuilding links; anything cached is dropped
+    fn replace_events(&mut self, events: Vec<Event>) {
+        self.events = Vec::new();
+        self.links.clear();
+        self.open.clear();
+        self.index = HistoryIndex::default();
+        self.sequence_cache.get_mut().takes.clear();
+
+        for event in events {
+            self.push(event);
+        }
+    }
+
     // TODO: Adjust logic now that beat break handling is done above (redundant?)
     pub fn add(&mut self, event: Event) {
         if self.is_silent() {
             if matches!(event, Event::Silence(_)) {
                 // Assume replacement of starting silence
-                self.events.clear();
+                self.replace_events(Vec::new());
             }
 
-            self.events.push(event);
-            self.modified = true;
+            self.record(event);
         } else {
-            if !matches!(event, Event::Silence(_)) {
+            match event {
                 // Ignore silence appended to running sequences
-                self.events.push(event);
+                Event::Silence(_) => {}
+                Event::Control(control) => self.record_control(control),
+                _ => self.record(event),
+            }
+        }
+    }
+
+    /*
+        A move closer than CONTROL_THINNING to the one before it on the same bus takes its place, so a sweep
+            is kept as a move every CONTROL_THINNING rather than every knob step. Only trailing moves are
+            looked at, which are always in the take being played.
+    */
+    fn record_control(&mut self, control: Control) {
+        let trailing = self.events.iter().rev().take_while(|event| matches!(event, Event::Control(_))).count();
+        let first_trailing = self.events.len() - trailing;
+
+        let previous = self.events[first_trailing..].iter().rposition(|event| match event {
+            Event::Control(previous) => previous.bus == control.bus,
+            _ => false,
+        });
+
+        match previous.map(|offset| first_trailing + offset) {
+            Some(index) if control.time.saturating_duration_since(self.events[index].time()) < CONTROL_THINNING => {
+                // Still the last move on the bus, so only the running value changes
+                set_control(&mut self.index.controls, &control);
+                self.events[index] = Event::Control(control);
+                self.redo_stack.clear();
                 self.modified = true;
             }
+            _ => self.record(Event::Control(control)),
         }
     }
 
     pub fn ends_on_sample(&self) -> bool {
         self.events
             .iter()
-            .last()
+            .rev()
+            .find(|event| !matches!(event, Event::Control(_)))
             .map(|a| {
                 return match (a) {
                     Event::NoteOn(note_on) => note_on.is_sample,
@@ -174,6 +933,198 @@ impl EventHistory {
             .unwrap_or(false)
     }
 
+    /*
+        Whether the notes of the given section end on a pad hit: its last note is a pad hit, with no beat break
+            after it and no release of a note in the section.
+    */
+    pub fn section_ends_on_sample(&self, section: &Section, per_instrument: bool) -> bool {
+        let Some(sound) = self.index.sounds.iter().find(|sound| sound.section(per_instrument) == *section) else {
+            return false;
+        };
+
+        // A section is all pad hits or none
+        sound.is_sample
+            && sound.last_off.is_none_or(|off| off < sound.last_on)
+            && self.index.last_rest.is_none_or(|rest| rest < sound.last_on)
+    }
+
+    pub fn has_notes(&self) -> bool {
+        !self.index.sounds.is_empty()
+    }
+
+    // Lanes with recorded notes, in order of first appearance
+    pub fn lanes(&self, per_instrument: bool) -> Vec<Lane> {
+        self.index
+            .sounds
+            .iter()
+            .map(|sound| Lane::new(sound.is_sample, &sound.instrument, per_instrument))
+            .unique()
+            .collect()
+    }
+
+    // Sections with recorded notes, in order of first appearance
+    pub fn sections(&self, per_instrument: bool) -> Vec<Section> {
+        self.index.sounds.iter().map(|sound| sound.section(per_instrument)).collect()
+    }
+
+    // Control buses moved while recording, in order of first move
+    pub fn control_buses(&self) -> Vec<i32> {
+        self.index.buses.iter().map(|(bus, _)| *bus).collect()
+    }
+
+    /*
+        Values of a control bus as a sequence, one value per grid step it changed in: the last one in the step,
+            rounded to <resolution>. Steps are counted from the start of history, and the last value lasts
+            until the end of it, so the lane lines up with the notes played alongside.
+    */
+    pub fn control_sequence(&self, bus: i32, resolution: &BigDecimal, options: &SequenceOptions) -> Vec<SequentialEvent> {
+        let (Some(origin), Some(last)) = (self.start_time(), self.events.last()) else {
+            return Vec::new();
+        };
+
+        let position = |time: Instant| {
+            options
+                .quantization
+                .snap_position(duration_to_beats(time.saturating_duration_since(origin), options.bpm))
+        };
+
+        let moves = self.index.buses.iter().find(|(moved, _)| *moved == bus).map(|(_, moves)| moves.as_slice());
+
+        // <position, value>, thinned to changes
+        let mut points: Vec<(BigDecimal, BigDecimal)> = Vec::new();
+        for index in moves.unwrap_or_default() {
+            let Event::Control(control) = &self.events[*index] else { continue };
+
+            let at = position(control.time);
+            let value = util::round_to_nearest(
+                BigDecimal::from_f32(control.value).unwrap_or(BigDecimal::zero()),
+                resolution.clone(),
+            );
+
+            if points.last().is_some_and(|(last_at, _)| *last_at == at) {
+                points.pop();
+            }
+
+            if points.last().map_or(true, |(_, last_value)| *last_value != value) {
+                points.push((at, value));
+            }
+        }
+
+        let end = position(last.time());
+        let mut sequence: Vec<SequentialEvent> = Vec::new();
+
+        let control_event = |representation: String, reserved_beats: BigDecimal| SequentialEvent {
+            representation,
+            reserved_beats,
+            sustain_beats: None,
+            velocity: None,
+            chord: None,
+            controls: Vec::new(),
+        };
+
+        // Keeps the offset from the start, as for lanes
+        if let Some((first_at, _)) = points.first().filter(|(first_at, _)| !first_at.is_zero()) {
+            sequence.push(control_event(SILENCE_REP.to_string(), first_at.clone()));
+        }
+
+        for (index, (at, value)) in points.iter().enumerate() {
+            let next = points.get(index + 1).map(|(next_at, _)| next_at.clone()).unwrap_or(end.clone());
+            let reserved = (next - at.clone()).max(BigDecimal::zero());
+            sequence.push(control_event(value.normalized().to_string(), reserved));
+        }
+
+        sequence
+    }
+
+    // Takes with notes in them, oldest first; loops where nothing was played are skipped
+    pub fn takes(&self) -> Vec<Take> {
+        self.take_bounds()
+            .iter()
+            .tuple_windows()
+            .map(|(start, end)| Take {
+                start: *start,
+                end: *end,
+                notes: self.events[*start..*end]
+                    .iter()
+                    .filter(|event| matches!(event, Event::NoteOn(_)))
+                    .count(),
+            })
+            .filter(|take| take.notes > 0)
+            .collect()
+    }
+
+    /*
+        Copy of history with the takes <first>..=<last> as returned by takes().
+        The opening beat break becomes a silence so the offset from the loop start is kept,
+            and releases recorded after the last take are brought along for sustain.
+    */
+    pub fn take_range(&self, first: usize, last: usize) -> Option<EventHistory> {
+        let takes = self.takes();
+        let start = takes.get(first)?.start;
+        let end = takes.get(last)?.end;
+
+        let mut events: Vec<Event> = self.events[start..end].to_vec();
+
+        if let Some(Event::BeatBreak(BeatBreak { time })) = events.first() {
+            events[0] = Event::Silence(Silence { time: *time });
+        }
+
+        let mut held: Vec<String> = Vec::new();
+        for event in &events {
+            match event {
+                Event::NoteOn(note_on) => held.push(note_on.id.clone()),
+                Event::NoteOff(note_off) => {
+                    if let Some(index) = held.iter().position(|id| *id == note_off.id) {
+                        held.remove(index);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for event in &self.events[end..] {
+            if let Event::NoteOff(note_off) = event {
+                if let Some(index) = held.iter().position(|id| *id == note_off.id) {
+                    held.remove(index);
+                    events.push(event.clone());
+                }
+            }
+        }
+
+        Some(EventHistory::from_events(events))
+    }
+
+    /*
+        Copy of history with only the notes of the given lane; silences and beat breaks are shared by all lanes.
+        A lane that starts later than history itself gets a leading silence to keep its offset.
+    */
+    pub fn lane(&self, lane: &Lane, per_instrument: bool) -> EventHistory {
+        let mut held: Vec<&str> = Vec::new();
+        let mut events: Vec<Event> = Vec::new();
+
+        for event in &self.events {
+            match event {
+                Event::NoteOn(note_on) => {
+                    if Lane::of(note_on, per_instrument) == *lane {
+                        held.push(&note_on.id);
+                        events.push(event.clone());
+                    } else if events.is_empty() {
+                        events.push(Event::Silence(Silence { time: note_on.time }));
+                    }
+                }
+                Event::NoteOff(note_off) => {
+                    if let Some(index) = held.iter().position(|id| *id == note_off.id) {
+                        held.remove(index);
+                        events.push(event.clone());
+                    }
+                }
+                _ => events.push(event.clone()),
+            }
+        }
+
+        EventHistory::from_events(events)
+    }
+
     fn is_silent(&self) -> bool {
         self.events.is_empty()
             || self
@@ -183,115 +1134,723 @@ impl EventHistory {
     }
 
     pub fn clear(&mut self) {
-        self.events.clear();
+        self.undo(UndoScope::All);
+    }
+
+    /*
+        Remove the last note, take or everything from history.
+        The removed events can be brought back with redo() until something new is recorded.
+    */
+    pub fn undo(&mut self, scope: UndoScope) {
+        let snapshot = self.events.clone();
+
+        match scope {
+            UndoScope::Note => self.remove_last_note(),
+            UndoScope::Take => self.remove_last_take(),
+            UndoScope::All => self.events.clear(),
+        }
+
+        if self.events != snapshot {
+            let events = std::mem::take(&mut self.events);
+            self.replace_events(events);
+            self.redo_stack.push(snapshot);
+            if self.redo_stack.len() > MAX_REDO {
+                self.redo_stack.remove(0);
+            }
+            self.modified = true;
+        }
+    }
+
+    pub fn redo(&mut self) {
+        if let Some(events) = self.redo_stack.pop() {
+            self.replace_events(events);
+            self.modified = true;
+        }
+    }
+
+    fn remove_last_note(&mut self) {
+        let last_on = self.events.iter().rposition(|event| matches!(event, Event::NoteOn(_)));
+
+        if let Some(index) = last_on {
+            if let Event::NoteOn(note_on) = self.events.remove(index) {
+                // The release, if any, is the first matching NoteOff after the press
+                let off = self.events[index..].iter().position(|event| match event {
+                    Event::NoteOff(note_off) => note_off.id == note_on.id,
+                    _ => false,
+                });
+
+                if let Some(offset) = off {
+                    self.events.remove(index + offset);
+                }
+            }
+        }
+    }
+
+    fn remove_last_take(&mut self) {
+        let last_break = self.events.iter().rposition(|event| matches!(event, Event::BeatBreak(_)));
+
+        match last_break {
+            // Nothing recorded since the break: the break itself closes the take to remove
+            Some(index) if index == self.events.len() - 1 => {
+                let previous_break = self.events[..index]
+                    .iter()
+                    .rposition(|event| matches!(event, Event::BeatBreak(_)));
 
+                self.events.truncate(previous_break.map(|i| i + 1).unwrap_or(0));
+            }
+            Some(index) => self.events.truncate(index + 1),
+            None => self.events.clear(),
+        }
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    // Replace all events, e.g. from a saved session; nothing can be redone past this
+    pub fn restore(&mut self, events: Vec<Event>) {
+        self.replace_events(events);
+        self.redo_stack.clear();
         self.modified = true;
     }
 
+    // Time of the first event, i.e. where history starts
+    pub fn start_time(&self) -> Option<Instant> {
+        self.events.first().map(Event::time)
+    }
+
+    // Played notes with how long they were held, if released
+    pub fn notes(&self) -> Vec<(NoteOn, Option<Duration>)> {
+        self.events
+            .iter()
+            .enumerate()
+            .filter_map(|(index, event)| match event {
+                Event::NoteOn(note_on) => Some((note_on.clone(), self.sustain_at(index))),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Start of history, each beat break and the end of history
+    fn take_bounds(&self) -> Vec<usize> {
+        std::iter::once(0)
+            .chain(self.index.breaks.iter().map(|(index, _)| *index))
+            .chain(std::iter::once(self.events.len()))
+            .collect()
+    }
+
+    // Time between the NoteOn at <index> and its release, if released
+    fn sustain_at(&self, index: usize) -> Option<Duration> {
+        match (&self.events[index], self.links[index]) {
+            (Event::NoteOn(note_on), Some(off_index)) => match &self.events[off_index] {
+                Event::NoteOff(note_off) => Some(note_off.time.duration_since(note_on.time)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Returns a sequential representation of the events in history as a shuttle-notation string.
+    pub fn as_sequence(&self, options: &SequenceOptions) -> Vec<SequentialEvent> {
+        self.sequence(None, options)
+    }
+
+    // As as_sequence(), for the notes of a single section; silences and beat breaks are shared, like in lane()
+    pub fn section_sequence(&self, section: &Section, per_instrument: bool, options: &SequenceOptions) -> Vec<SequentialEvent> {
+        self.sequence(Some((section, per_instrument)), options)
+    }
+
     /*
-        Find the first following NoteOff, matching the given NoteOn,
-            and infer the time passed between them.
+        Sequenced one take at a time: chords and grid anchors never span a beat break, so a finished take
+            only needs the time of the break that ends it. Finished takes are cached, and only the
+            take being played is sequenced again for each new event.
     */
-    pub fn get_sustain_dur(&self, event: &NoteOn) -> Option<Duration> {
-        let mut off_match: Option<&NoteOff> = None;
-        let mut self_found = false;
-
-        // TODO: Could much more efficiently lookup a starting point of the loop...
-        for iter_event in &self.events {
-            if !self_found {
-                if let Event::NoteOn(note_on) = iter_event {
-                    if note_on == event {
-                        self_found = true;
-                    }
-                }
-            } else {
-                if let Event::NoteOff(note_off) = iter_event {
-                    if event.id == note_off.id {
-                        off_match = Some(&note_off);
-                        break;
+    fn sequence(&self, section: Option<(&Section, bool)>, options: &SequenceOptions) -> Vec<SequentialEvent> {
+        let mut cache = self.sequence_cache.borrow_mut();
+        if cache.options.as_ref() != Some(options) {
+            cache.options = Some(options.clone());
+            cache.takes.clear();
+        }
+
+        let mut bounds = self.take_bounds();
+        bounds.dedup();
+
+        let mut sequence = Vec::new();
+
+        for (start, end) in bounds.into_iter().tuple_windows() {
+            let key = (section.map(|(section, _)| section.clone()), start);
+
+            match cache.takes.get(&key) {
+                Some((cached_end, cached)) if *cached_end == end => sequence.extend(cached.iter().cloned()),
+                _ => {
+                    let take = self.take_sequence(start, end, section, options);
+                    sequence.extend(take.iter().cloned());
+
+                    if end < self.events.len() {
+                        cache.takes.insert(key, (end, take));
                     }
                 }
             }
         }
 
-        off_match.map(|note_off| note_off.time.duration_since(event.time))
+        sequence
     }
 
-    // Returns a sequential representation of the events in history as a shuttle-notation string.
-    pub fn as_sequence(&self, bpm: i64, quantization: BigDecimal) -> Vec<SequentialEvent> {
-        let mut next_note_time: Option<Instant> = None;
+    // Sequence of the events in <start>..<end>, ending at the beat break after it if there is one
+    fn take_sequence(
+        &self,
+        start: usize,
+        end: usize,
+        section: Option<(&Section, bool)>,
+        options: &SequenceOptions,
+    ) -> Vec<SequentialEvent> {
+        let in_section = |note_on: &NoteOn| {
+            section.map_or(true, |(section, per_instrument)| Section::of(note_on, per_instrument) == *section)
+        };
 
-        let mut notes: Vec<SequentialEvent> = self
-            .events
+        // Same as lane(): a section that starts later than history itself gets a leading silence
+        let lead = match self.index.first.map(|index| &self.events[index]) {
+            Some(Event::NoteOn(note_on)) if start == 0 && !in_section(note_on) => {
+                Some(Event::Silence(Silence { time: note_on.time }))
+            }
+            _ => None,
+        };
+
+        // Control values carry over from earlier takes
+        let mut controls: Vec<(i32, f32)> = match self.index.breaks.binary_search_by_key(&start, |(index, _)| *index) {
+            Ok(position) => self.index.breaks[position].1.clone(),
+            Err(_) => Vec::new(),
+        };
+
+        let entries: Vec<TimelineInput> = lead
             .iter()
-            .rev() // Iter backwards to always have the next event time available
-            .filter_map(|event| match event {
-                Event::NoteOn(note_on) => {
-                    let time = next_note_time
-                        .map(|next| next.duration_since(note_on.time))
-                        .unwrap_or(Duration::ZERO);
-
-                    next_note_time = Some(note_on.time.clone());
-
-                    let sustain_beats: Option<BigDecimal> =
-                        self.get_sustain_dur(note_on).map(|dur| {
-                            util::round_to_nearest(
-                                duration_to_beats(dur, bpm),
-                                quantization.clone(),
-                            )
-                        });
-
-                    let time_beats =
-                        util::round_to_nearest(duration_to_beats(time, bpm), quantization.clone());
-
-                    Some(SequentialEvent {
-                        representation: note_on.id.to_string(),
-                        reserved_beats: time_beats,
-                        sustain_beats,
-                    })
+            .map(|event| (event, None, Vec::new()))
+            .chain(self.events[start..end].iter().enumerate().filter_map(|(offset, event)| match event {
+                Event::NoteOn(note_on) if in_section(note_on) => {
+                    Some((event, self.sustain_at(start + offset), controls.clone()))
                 }
-                // TODO: Combine these two - only representation differs
-                Event::Silence(silence) => {
-                    let time = next_note_time
-                        .map(|next| next.duration_since(silence.time.clone()))
-                        .unwrap_or(Duration::ZERO);
-
-                    next_note_time = Some(silence.time);
-
-                    let time_beats =
-                        util::round_to_nearest(duration_to_beats(time, bpm), quantization.clone());
-
-                    Some(SequentialEvent {
-                        representation: SILENCE_REP.to_string(),
-                        reserved_beats: time_beats,
-                        sustain_beats: None,
-                    })
+                Event::Control(control) => {
+                    set_control(&mut controls, control);
+                    None
                 }
-                Event::BeatBreak(beatbreak) => {
-                    let time = next_note_time
-                        .map(|next| next.duration_since(beatbreak.time.clone()))
-                        .unwrap_or(Duration::ZERO);
+                Event::NoteOn(_) | Event::NoteOff(_) => None,
+                _ => Some((event, None, Vec::new())),
+            }))
+            .collect();
+
+        let next_break = match self.events.get(end) {
+            Some(Event::BeatBreak(BeatBreak { time })) => Some(*time),
+            _ => None,
+        };
 
-                    next_note_time = Some(beatbreak.time);
+        sequence_of(timeline(entries, options.chord_window), next_break, options)
+    }
+}
 
-                    let time_beats =
-                        util::round_to_nearest(duration_to_beats(time, bpm), quantization.clone());
+/*
+    Events in play order, with the time each should be considered to start at.
+    Notes starting within <chord_window> of each other are taken as a chord: they share the onset of
+        the first note, are ordered low to high, and the first one carries the chord name.
+    Notation has no chord syntax, so this only shows as zero-length steps: "c5:0 e5:0 g5:1".
+*/
+fn timeline<'a>(entries: Vec<TimelineInput<'a>>, chord_window: Duration) -> Vec<TimelineEntry<'a>> {
+    let mut timeline: Vec<TimelineEntry> = Vec::new();
+    let mut chord_start: Option<Instant> = None;
 
-                    Some(SequentialEvent {
-                        representation: BEAT_BREAK_REP.to_string(),
-                        reserved_beats: time_beats,
-                        sustain_beats: None,
-                    })
+    for (event, sustain, controls) in entries {
+        let start = match event {
+            Event::NoteOn(note_on) => match chord_start {
+                Some(start) if note_on.time.duration_since(start) <= chord_window => start,
+                _ => {
+                    chord_start = Some(note_on.time);
+                    note_on.time
                 }
+            },
+            Event::Silence(Silence { time }) | Event::BeatBreak(BeatBreak { time }) => {
+                chord_start = None;
+                *time
+            }
+            Event::NoteOff(_) | Event::Control(_) => continue,
+        };
+
+        timeline.push(TimelineEntry { event, sustain, controls, start, chord: None });
+    }
+
+    if chord_window.is_zero() {
+        return timeline;
+    }
+
+    let mut start = 0;
+    while start < timeline.len() {
+        let onset = timeline[start].start;
+        let end = start
+            + timeline[start..]
+                .iter()
+                .take_while(|entry| matches!(entry.event, Event::NoteOn(_)) && entry.start == onset)
+                .count()
+                .max(1);
+
+        if end - start > 1 {
+            let chord = &mut timeline[start..end];
+            chord.sort_by_key(|entry| match entry.event {
+                Event::NoteOn(note_on) => pitch_of(note_on).unwrap_or(0),
+                _ => 0,
+            });
+
+            let tones: Vec<u8> = chord
+                .iter()
+                .filter_map(|entry| match entry.event {
+                    Event::NoteOn(note_on) if !note_on.is_sample => pitch_of(note_on),
+                    _ => None,
+                })
+                .collect();
+
+            chord[0].chord = chords::chord_name(&tones);
+        }
+
+        start = end;
+    }
+
+    timeline
+}
+
+// Sequence a timeline; <next_break> is the onset following it, if any
+fn sequence_of(timeline: Vec<TimelineEntry>, next_break: Option<Instant>, options: &SequenceOptions) -> Vec<SequentialEvent> {
+    let bpm = options.bpm;
+    let quantization = &options.quantization;
+
+    // Grid positions are counted from the last beat break, or from the first event before any break
+    let mut anchor: Option<Instant> = None;
+    let anchors: Vec<Instant> = timeline
+        .iter()
+        .map(|entry| {
+            if matches!(entry.event, Event::BeatBreak(_)) || anchor.is_none() {
+                anchor = Some(entry.start);
+            }
+            anchor.unwrap()
+        })
+        .collect();
+
+    /*
+        Relative positions of every onset, <next_break> included, counted from the take start.
+        Gaps are snapped one by one, but rounded as a running total so tuplets add up;
+            swing needs a grid origin, so swung takes snap each onset from the take start instead.
+    */
+    let onsets: Vec<Instant> = timeline.iter().map(|entry| entry.start).chain(next_break).collect();
+    let relative: Vec<BigDecimal> = match (options.quantize_mode, onsets.first()) {
+        (QuantizeMode::Relative, Some(take_start)) if quantization.is_swung() => onsets
+            .iter()
+            .map(|time| quantization.snap_position(duration_to_beats(time.duration_since(*take_start), bpm)))
+            .collect(),
+        (QuantizeMode::Relative, Some(_)) => {
+            let mut position = BigDecimal::zero();
+            std::iter::once(BigDecimal::zero())
+                .chain(onsets.windows(2).map(|pair| {
+                    position = position.clone() + quantization.snap_gap(duration_to_beats(pair[1].duration_since(pair[0]), bpm));
+                    quantization.round(position.clone())
+                }))
+                .collect()
+        }
+        _ => Vec::new(),
+    };
+
+    // Beats reserved between onset <index> and the next one
+    let reserved = |index: usize, start_time: Instant, next: Instant, anchor: Instant| match options.quantize_mode {
+        QuantizeMode::Relative => relative[index + 1].clone() - relative[index].clone(),
+        QuantizeMode::Grid => {
+            // Difference of snapped positions, so rounding never accumulates
+            let position = |time: Instant| {
+                quantization.snap_position(duration_to_beats(time.saturating_duration_since(anchor), bpm))
+            };
+            position(next) - position(start_time)
+        }
+    };
+
+    let mut next_note_time: Option<Instant> = next_break;
+
+    let mut notes: Vec<SequentialEvent> = timeline
+        .into_iter()
+        .zip(anchors)
+        .enumerate()
+        .rev() // Iter backwards to always have the next event time available
+        .filter_map(|(index, (entry, anchor))| {
+            let time_beats = next_note_time
+                .map(|next| reserved(index, entry.start, next, anchor))
+                .unwrap_or(BigDecimal::zero());
+
+            next_note_time = Some(entry.start);
+
+            match entry.event {
+                Event::NoteOn(note_on) => Some(SequentialEvent {
+                    representation: note_on.id.to_string(),
+                    reserved_beats: time_beats,
+                    // Left unrounded; each sustain output mode has its own grid
+                    sustain_beats: entry.sustain.map(|dur| duration_to_beats(dur, bpm)),
+                    velocity: Some(note_on.velocity),
+                    chord: entry.chord,
+                    controls: entry.controls,
+                }),
+                Event::Silence(_) | Event::BeatBreak(_) => Some(SequentialEvent {
+                    representation: if matches!(entry.event, Event::Silence(_)) {
+                        SILENCE_REP.to_string()
+                    } else {
+                        BEAT_BREAK_REP.to_string()
+                    },
+                    reserved_beats: time_beats,
+                    sustain_beats: None,
+                    velocity: None,
+                    chord: None,
+                    controls: Vec::new(),
+                }),
                 _ => None,
-            })
-            .collect();
+            }
+        })
+        .collect();
 
-        notes.reverse();
+    notes.reverse();
 
-        notes
+    notes
+}
+
+fn set_control(controls: &mut Vec<(i32, f32)>, control: &Control) {
+    match controls.iter_mut().find(|(bus, _)| *bus == control.bus) {
+        Some(entry) => entry.1 = control.value,
+        None => controls.push((control.bus, control.value)),
+    }
+}
+
+// Sort key within chords: midi note for keys, sample index for pads
+fn pitch_of(note_on: &NoteOn) -> Option<u8> {
+    if note_on.is_sample {
+        note_on.id.parse::<u8>().ok()
+    } else {
+        midi_translation::oletter_to_tone(&note_on.id)
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+    use crate::event_model::NoteOff;
+
+    // Default config, with chord names, a quarter beat grid and control values rounded coarsely enough to merge
+    fn config() -> Config {
+        Config {
+            quantization: "0.25".to_string(),
+            chord_annotations: true,
+            chord_window_ms: 0,
+            control_resolution: 0.05,
+            control_arg_names: vec!["cutoff".to_string()],
+            ..Config::default()
+        }
+    }
+
+    fn options() -> StringifyOptions {
+        StringifyOptions::from_config(&config())
+    }
+
+    fn sustained(mode: SustainMode) -> StringifyOptions {
+        let options = options();
+        StringifyOptions { sustain: SustainOutput { mode, ..options.sustain }, ..options }
+    }
+
+    fn sequence_options() -> SequenceOptions {
+        SequenceOptions::from_config(&config())
+    }
+
+    fn render(history: &EventHistory, options: &StringifyOptions) -> String {
+        stringify_history(history.as_sequence(&sequence_options()), options)
+    }
+
+    fn render_lanes(history: &EventHistory, selection: &str, options: &StringifyOptions) -> String {
+        stringify_lanes(history, selection, false, &sequence_options(), options)
+    }
+
+    fn history_of(events: Vec<Event>) -> EventHistory {
+        let mut history = EventHistory::new();
+        for event in events {
+            history.add(event);
+        }
+        history
+    }
+
+    // Events at milliseconds from a common start; a beat is 500ms at the default 120 bpm
+    struct Played(Instant);
+
+    impl Played {
+        fn at(&self, ms: u64) -> Instant {
+            self.0 + Duration::from_millis(ms)
+        }
+
+        fn note(&self, id: &str, ms: u64) -> NoteOn {
+            NoteOn { id: id.to_string(), time: self.at(ms), is_sample: false, velocity: 127, instrument: "aPad".to_string(), args: Vec::new() }
+        }
+
+        fn on(&self, id: &str, ms: u64) -> Event {
+            Event::NoteOn(self.note(id, ms))
+        }
+
+        fn off(&self, id: &str, ms: u64) -> Event {
+            Event::NoteOff(NoteOff { id: id.to_string(), time: self.at(ms) })
+        }
+
+        fn pad(&self, sample: i32, ms: u64) -> Event {
+            Event::NoteOn(NoteOn { is_sample: true, instrument: "EMU_EDrum".to_string(), ..self.note(&sample.to_string(), ms) })
+        }
+
+        fn control(&self, value: f32, ms: u64) -> Event {
+            Event::Control(Control { bus: 0, value, time: self.at(ms) })
+        }
+    }
+
+    #[test]
+    fn verify() {
+        let played = Played(Instant::now());
+        let beats = |text: &str| BigDecimal::from_str(text).unwrap();
+
+        // Velocity is written as an amp arg, in a few levels
+        let history = history_of(vec![Event::NoteOn(NoteOn { velocity: 100, ..played.note("c5", 0) }), played.on("e5", 500)]);
+        assert_eq!(render(&history, &options()), "(c5:1,amp0.3 e5:3):len4,tot1.00");
+
+        let velocity = options().velocity.unwrap();
+        assert_eq!(velocity.quantize(127), 127);
+        assert_eq!(velocity.quantize(3), 32);
+        assert_eq!(velocity.format(120), None);
+
+        // Sustain: c5 held for 0.4 of its 1 beat, e5 held 0.9 beats
+        let history = history_of(vec![played.on("c5", 0), played.off("c5", 200), played.on("e5", 500), played.off("e5", 950)]);
+        assert_eq!(render(&history, &sustained(SustainMode::Off)), "(c5:1 e5:3):len4,tot1.00");
+        assert_eq!(render(&history, &sustained(SustainMode::Absolute)), "(c5:1,sus0.5 e5:3,sus1):len4,tot1.00");
+        assert_eq!(render(&history, &sustained(SustainMode::Relative)), "(c5:1,sus*1 e5:3,sus*3):len4,tot1.00");
+        assert_eq!(render(&history, &sustained(SustainMode::Legato)), "(c5:1,legato0.5 e5:3,legato0.25):len4,tot1.00");
+
+        // Loop length: power of two bars is the same as the old power of two beats in 4/4
+        let four_four = TimeSignature { beats: 4, unit: 4 };
+        let six_eight = TimeSignature { beats: 6, unit: 8 };
+        assert_eq!(LoopLength::PowerOfTwoBars.length(&beats("0"), &four_four), beats("4"));
+        assert_eq!(LoopLength::PowerOfTwoBars.length(&beats("5"), &four_four), beats("8"));
+        assert_eq!(LoopLength::PowerOfTwoBars.length(&beats("12.5"), &four_four), beats("16"));
+
+        // A 3 bar phrase stays 3 bars
+        assert_eq!(LoopLength::NextBar.length(&beats("11.5"), &four_four), beats("12"));
+        assert_eq!(LoopLength::NextBar.length(&beats("7"), &six_eight), beats("9"));
+        assert_eq!(LoopLength::PowerOfTwoBars.length(&beats("7"), &six_eight), beats("12"));
+
+        assert_eq!(LoopLength::Exact.length(&beats("2.5"), &four_four), beats("2.5"));
+        assert_eq!(LoopLength::Bars(2).length(&beats("3"), &four_four), beats("8"));
+        assert_eq!(LoopLength::Bars(2).length(&beats("9"), &four_four), beats("16"));
+
+        let history = history_of(vec![played.on("c5", 0), played.on("e5", 2500)]);
+        let in_six_eight = |loop_length: LoopLength| {
+            render(&history, &StringifyOptions { loop_length, time_signature: six_eight, ..options() })
+        };
+        assert_eq!(in_six_eight(LoopLength::NextBar), "(c5:5 e5:1):len6,tot5.00");
+        assert_eq!(in_six_eight(LoopLength::Exact), "(c5:5 e5:0):len5,tot5.00");
+
+        // Repeated bars: the same bar twice, then a closing note
+        let history = history_of(vec![
+            played.on("c5", 0),
+            played.on("e5", 1000),
+            played.on("c5", 2000),
+            played.on("e5", 3000),
+            played.on("g5", 4000),
+        ]);
+        let repeats = |multiline: bool, compress_repeats: bool| {
+            render(&history, &StringifyOptions { multiline, compress_repeats, ..options() })
+        };
+        assert_eq!(repeats(false, false), "(c5:2 e5:2 c5:2 e5:2 g5:0):len8,tot8.00");
+        assert_eq!(repeats(false, true), "$a = c5:2 e5:2\n$b = g5:0\n($a $a $b):len8,tot8.00");
+        assert_eq!(repeats(true, false), "$a = c5:2 e5:2\n$b = g5:0\n$a $a $b");
+
+        let phrases = crate::shuttle::parse(&repeats(false, true), "legato").unwrap();
+        assert_eq!(phrases[0].notes.len(), 5);
+        assert_eq!(phrases[0].length, BigDecimal::from(8));
+
+        // Chords: sloppy C major with g5 first and c5 and e5 within 40ms, then a single a5 a beat later
+        let history = history_of(vec![played.on("g5", 0), played.on("c5", 20), played.on("e5", 35), played.on("a5", 500)]);
+        let chord_window = SequenceOptions { chord_window: Duration::from_millis(40), ..sequence_options() };
+        assert_eq!(
+            stringify_history(history.as_sequence(&chord_window), &options()),
+            "# chords: C\n(c5:0 e5:0 g5:1 a5:3):len4,tot1.00"
+        );
+        assert_eq!(render(&history, &options()), "(g5:0 c5:0 e5:1 a5:3):len4,tot1.00");
+
+        // Grid quantize: notes 0.6 beats apart, rounded to half beats
+        let mut history = history_of(vec![Event::BeatBreak(BeatBreak { time: played.at(0) })]);
+        for i in 0..5 {
+            history.add(played.on("c5", i * 300));
+        }
+
+        // Each gap rounds down on its own, so the phrase falls behind the beat
+        let relative = SequenceOptions {
+            quantization: Quantization { step: beats("0.5"), ..sequence_options().quantization },
+            ..sequence_options()
+        };
+        assert_eq!(
+            stringify_history(history.as_sequence(&relative), &options()),
+            "(. c5:0.5 c5:0.5 c5:0.5 c5:0.5 c5:2):len4,tot2.0"
+        );
+
+        // Snapped from the break, onsets 0.6, 1.2, 1.8 and 2.4 land on 0.5, 1, 2 and 2.5
+        let grid = SequenceOptions { quantize_mode: QuantizeMode::Grid, ..relative };
+        assert_eq!(
+            stringify_history(history.as_sequence(&grid), &options()),
+            "(. c5:0.5 c5:0.5 c5:1 c5:0.5 c5:1.5):len4,tot2.5"
+        );
+
+        // Lanes: a bassline with a pad hit on every other beat, starting a beat in
+        let history = history_of(vec![played.on("c3", 0), played.pad(1, 500), played.on("g3", 1000), played.pad(2, 1500)]);
+        assert_eq!(history.lanes(false), vec![Lane::Synth, Lane::Pads]);
+        assert_eq!(history.lanes(true), vec![Lane::Instrument("aPad".to_string()), Lane::Pads]);
+        assert_eq!(render_lanes(&history, "synth", &options()), "(c3:2 g3:2):len4,tot2.00");
+        assert_eq!(render_lanes(&history, "pads", &options()), "(x:1 1:2 2:1):len4,tot3.00");
+        assert_eq!(
+            render_lanes(&history, ALL_LANES, &options()),
+            "# synth\n(c3:2 g3:2):len4,tot2.00\n# pads\n(x:1 1:2 2:1):len4,tot3.00"
+        );
+
+        // Sections: switching to another instrument halfway through the phrase
+        let amp = vec![OscType::String("amp".to_string()), OscType::Float(0.5)];
+        let mut history = history_of(vec![
+            Event::NoteOn(NoteOn { args: amp.clone(), ..played.note("c3", 0) }),
+            Event::NoteOn(NoteOn { args: amp, ..played.note("g3", 1000) }),
+        ]);
+        let headed = StringifyOptions { section_headers: true, ..options() };
+        assert_eq!(render_lanes(&history, ALL_LANES, &options()), "(c3:2 g3:2):len4,tot2.00");
+        assert_eq!(render_lanes(&history, ALL_LANES, &headed), "# synth: aPad amp0.5\n(c3:2 g3:2):len4,tot2.00");
+
+        history.add(Event::NoteOn(NoteOn { instrument: "bass".to_string(), ..played.note("c3", 1500) }));
+        assert_eq!(history.lanes(false), vec![Lane::Synth]);
+        assert_eq!(
+            render_lanes(&history, ALL_LANES, &options()),
+            "# synth: aPad amp0.5\n(c3:2 g3:2):len4,tot2.00\n# synth: bass\n(x:3 c3:1):len4,tot3.00"
+        );
+
+        // Controls: a filter sweep under two notes; moves within the thinning window merge
+        let history = history_of(vec![
+            played.on("c3", 0),
+            played.control(0.2, 0),
+            played.control(0.31, 10),
+            played.control(0.41, 500),
+            played.control(0.42, 600),
+            played.on("g3", 1000),
+            played.control(0.8, 1500),
+        ]);
+        assert_eq!(history.events.len(), 6);
+        assert_eq!(history.control_buses(), vec![0]);
+
+        let controls = |mode: ControlMode| {
+            let options = StringifyOptions { controls: ControlOutput { mode, ..options().controls }, ..options() };
+            render_lanes(&history, ALL_LANES, &options)
+        };
+
+        // Same step and same rounded value are thinned out
+        let lanes = controls(ControlMode::Lanes);
+        assert_eq!(lanes, "(c3:2 g3:2):len4,tot2.00\n# bus 0\n(0.3:1 0.4:2 0.8:1):len4,tot3.00");
+        // The knob was first moved after c3 started
+        assert_eq!(controls(ControlMode::Args), "(c3:2 g3:2,cutoff0.4):len4,tot2.00");
+        assert_eq!(controls(ControlMode::Off), "(c3:2 g3:2):len4,tot2.00");
+
+        // Control lanes read back as values of their bus, next to the notes
+        let phrases = crate::shuttle::parse(&lanes, "legato").unwrap();
+        assert_eq!(phrases.iter().map(|phrase| phrase.bus).collect::<Vec<_>>(), vec![None, Some(0)]);
+        let values: Vec<(&str, BigDecimal, bool)> =
+            phrases[1].notes.iter().map(|note| (note.id.as_str(), note.at.clone(), note.is_sample)).collect();
+        assert_eq!(values, vec![("0.3", beats("0"), false), ("0.4", beats("1"), false), ("0.8", beats("3"), false)]);
+
+        // Takes, with an empty loop in between
+        let history = history_of(vec![
+            played.on("c5", 0),
+            Event::BeatBreak(BeatBreak { time: played.at(2000) }),
+            Event::BeatBreak(BeatBreak { time: played.at(4000) }),
+            played.on("e5", 4500),
+            played.on("g5", 5000),
+            played.off("g5", 6500),
+            Event::BeatBreak(BeatBreak { time: played.at(6000) }),
+            played.off("e5", 6500),
+        ]);
+        assert_eq!(history.takes().iter().map(|take| take.notes).collect::<Vec<_>>(), vec![1, 2]);
+
+        // The second take starts half a beat into its loop; e5 is released in the next loop
+        let second = history.take_range(1, 1).unwrap();
+        assert_eq!(render(&second, &sustained(SustainMode::Absolute)), "(x:1 e5:1,sus4 g5:2,sus3):len4,tot2.00");
+
+        // Ranges keep the loops in between, played or not
+        let both = history.take_range(0, 1).unwrap();
+        assert_eq!(render(&both, &options()), "(c5:4 . x:4 . x:1 e5:1 g5:6):len16,tot10.00");
+        assert!(history.take_range(0, 2).is_none());
+
+        // Incremental sequencing renders the same as the same events without anything cached
+        let mut history = history_of(vec![played.on("c5", 0), played.pad(1, 250)]);
+        history.register_beatbreak(played.at(2000));
+        history.add(played.on("e5", 2500));
+
+        let absolute = sustained(SustainMode::Absolute);
+        let fresh = |history: &EventHistory| EventHistory::from_events(history.events().to_vec());
+        assert_eq!(render_lanes(&history, ALL_LANES, &absolute), render_lanes(&fresh(&history), ALL_LANES, &absolute));
+
+        // c5 is released in the next loop; the finished take it is in picks up the sustain
+        history.add(played.off("c5", 3000));
+        assert_eq!(history.links[0], Some(4));
+        assert_eq!(history.links[4], Some(0));
+        assert_eq!(render_lanes(&history, "synth", &absolute), "(c5:4,sus6 . x:1 e5:3):len8,tot5.00");
+        assert_eq!(render_lanes(&history, ALL_LANES, &absolute), render_lanes(&fresh(&history), ALL_LANES, &absolute));
+
+        // Undoing the take takes the release with it
+        history.undo(UndoScope::Take);
+        assert_eq!(history.links[0], None);
+        assert_eq!(render_lanes(&history, ALL_LANES, &absolute), render_lanes(&fresh(&history), ALL_LANES, &absolute));
+        assert!(!render_lanes(&history, "synth", &absolute).contains("sus"));
+
+        // Undo and redo
+        let mut history = history_of(vec![played.on("c5", 0), played.off("c5", 100)]);
+        history.register_beatbreak(played.at(500));
+        history.add(played.on("e5", 600));
+        history.add(played.on("g5", 700));
+        history.add(played.off("e5", 800));
+
+        history.undo(UndoScope::Note);
+        assert_eq!(history.events.len(), 5);
+        assert!(!history.events.contains(&played.on("g5", 700)));
+
+        history.undo(UndoScope::Note);
+        assert_eq!(history.events.len(), 3);
+
+        history.redo();
+        assert_eq!(history.events.len(), 5);
+
+        history.undo(UndoScope::Take);
+        assert_eq!(history.events.len(), 3);
+        assert!(matches!(history.events.last(), Some(Event::BeatBreak(_))));
+
+        // Empty take: the break goes too, along with the take before it
+        history.undo(UndoScope::Take);
+        assert!(history.events.is_empty());
+
+        history.redo();
+        history.redo();
+        assert_eq!(history.events.len(), 5);
+
+        history.clear();
+        assert!(history.events.is_empty());
+        history.redo();
+        assert_eq!(history.events.len(), 5);
+
+        // New notes make earlier undo steps irrelevant
+        history.undo(UndoScope::Note);
+        history.add(played.on("a5", 900));
+        history.redo();
+        assert!(history.events.contains(&played.on("a5", 900)));
+
+        // So do releases and breaks
+        history.undo(UndoScope::Note);
+        history.add(played.off("a5", 1000));
+        history.redo();
+        assert!(history.events.contains(&played.off("a5", 1000)));
+
+        history.undo(UndoScope::Note);
+        history.register_beatbreak(played.at(1100));
+        history.redo();
+        assert!(matches!(history.events.last(), Some(Event::BeatBreak(_))));
+    }
+}
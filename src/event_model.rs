@@ -1,32 +1,60 @@
 use std::time::Instant;
 
-#[derive(PartialEq, Debug)]
+use rosc::OscType;
+
+#[derive(Clone, PartialEq, Debug)]
 pub enum Event {
     NoteOn(NoteOn),
     NoteOff(NoteOff),
     Silence(Silence),
     BeatBreak(BeatBreak),
+    Control(Control),
+}
+
+impl Event {
+    pub fn time(&self) -> Instant {
+        match self {
+            Event::NoteOn(NoteOn { time, .. })
+            | Event::NoteOff(NoteOff { time, .. })
+            | Event::Control(Control { time, .. })
+            | Event::Silence(Silence { time })
+            | Event::BeatBreak(BeatBreak { time }) => *time,
+        }
+    }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub struct BeatBreak {
     pub time: Instant,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub struct NoteOn {
     pub id: String,
     pub time: Instant,
     pub is_sample: bool,
+    pub velocity: u8,
+    // Synth instrument, or sample pack for pads
+    pub instrument: String,
+    // Synth or pad args at the time the note was played
+    pub args: Vec<OscType>,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub struct NoteOff {
     pub id: String,
     pub time: Instant,
 }
 
-#[derive(PartialEq, Debug)]
+// Knob move, as sent to the control bus
+#[derive(Clone, PartialEq, Debug)]
+pub struct Control {
+    pub bus: i32,
+    pub value: f32,
+    pub time: Instant,
+}
+
+#[derive(Clone, PartialEq, Debug)]
 pub struct Silence {
     pub time: Instant,
 }
@@ -1,4 +1,13 @@
 
+use std::sync::{Arc, Mutex};
+
+use ringbuf::storage::Heap;
+use ringbuf::wrap::caching::Caching;
+use ringbuf::SharedRb;
+
+use crate::event_history::UndoScope;
+use crate::playback::PhraseSource;
+
 /*
     CHARTING:
 
@@ -59,6 +68,18 @@ Mapping plan:
 
 */
 
+// Letters used for pads, in pad id order (pad 1 is 'a'). Shared with OSC letter-to-index configuration.
+pub const PAD_KEYS: [char; 16] = [
+    'a', 's', 'd', 'f', 'g', 'h', 'j', 'k', 'l', 'z', 'x', 'c', 'v', 'b', 'n', 'm',
+];
+
+pub fn pad_id_for_letter(letter: char) -> Option<u8> {
+    PAD_KEYS.iter().position(|&e| e == letter).map(|index| index as u8 + 1)
+}
+
+// Processor input pipe. Shared behind a mutex since the TUI, OSC and MIDI input all publish to it.
+pub type MIDIEventPublisher = Arc<Mutex<Caching<Arc<SharedRb<Heap<MIDIEvent>>>, true, false>>>;
+
 #[derive(Debug)]
 pub enum MIDIEvent {
     Key(Key),
@@ -72,12 +93,31 @@ pub enum MIDIEvent {
 #[derive(Debug)]
 pub enum NcursesCommand {
     ToggleMode,
+    ToggleMetronome,
     ToggleRecording,
+    CycleRecordMode,
     ToggleQuantize,
+    CycleQuantizeMode,
+    SetGrid(String),
+    AdjustSwing(i32),
+    AdjustStrength(i32),
     ToggleMultiline,
+    CycleSustainMode,
+    CycleOutputLane,
     CyclePadBank,
     SetInstrument(String),
     SetPack(String),
+    Undo(UndoScope),
+    Redo,
+    // Copy a range of takes, by index in EventHistory::takes()
+    CopyTakes(usize, usize),
+    // Export history, or a range of takes, as MIDI files
+    ExportMidi(Option<(usize, usize)>),
+    // Play Shuttle Notation, looping if set
+    PlayPhrase(PhraseSource, bool),
+    StopPhrase,
+    // Loop the clipboard contents, or stop if already playing
+    ToggleAudition,
 }
 
 #[derive(Debug)]
@@ -0,0 +1,38 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/*
+    Status messages from the background threads.
+    Printing straight to the terminal garbles the TUI, so unless running headless
+        messages are buffered until the TUI picks them up for its event log.
+*/
+
+const MAX_BUFFERED: usize = 100;
+
+static HEADLESS: AtomicBool = AtomicBool::new(false);
+static BUFFER: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+pub fn set_headless(headless: bool) {
+    HEADLESS.store(headless, Ordering::Relaxed);
+}
+
+pub fn is_headless() -> bool {
+    HEADLESS.load(Ordering::Relaxed)
+}
+
+pub fn log(message: String) {
+    if is_headless() {
+        eprintln!("{}", message);
+    } else {
+        let mut buffer = BUFFER.lock().unwrap();
+        buffer.push_back(message);
+        while buffer.len() > MAX_BUFFERED {
+            buffer.pop_front();
+        }
+    }
+}
+
+pub fn drain() -> Vec<String> {
+    BUFFER.lock().unwrap().drain(..).collect()
+}
@@ -3,50 +3,94 @@
 
 extern crate core;
 
+use std::collections::HashSet;
 use std::error::Error;
 use std::io::{stdin, Write};
-use std::net::{SocketAddrV4, UdpSocket};
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::path::Path;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::thread::sleep;
 use std::time::{Duration, Instant, SystemTime};
 
-use bigdecimal::{BigDecimal, ToPrimitive};
+use bigdecimal::ToPrimitive;
 use jdw_osc_lib::osc_stack::OSCStack;
 use midir::{Ignore, MidiInput};
 use ncurses_daemon::{KeyboardModeState, NcursesDaemon};
 use ringbuf::traits::{Consumer, Producer, Split};
 use ringbuf::HeapRb;
-use wl_clipboard_rs::copy::{MimeType, Options, Source};
+use rosc::OscType;
 
-use crate::event_history::EventHistory;
-use crate::event_model::{Event, NoteOff, NoteOn, Silence};
-use crate::keyboard_model::{MIDIEvent, NcursesCommand};
+use crate::cli::CliArgs;
+use crate::event_history::{EventHistory, LoopLength, QuantizeMode, SustainMode, UndoScope};
+use crate::event_model::{Control, Event, NoteOff, NoteOn, Silence};
+use crate::keyboard_model::{pad_id_for_letter, AbsPad, Key, MIDIEvent, NcursesCommand};
+use crate::midi_export::ExportOptions;
 use crate::midi_mapping::map;
 use crate::osc_client::OscClient;
+use crate::output::Outputs;
+use crate::playback::{PhraseSource, Player};
+use crate::recording::RecordMode;
+use crate::session::Session;
 use crate::state::{KeyboardMode, State};
+use crate::time_signature::TimeSignature;
 
 use itertools::Itertools;
 
+mod chords;
 mod event_history;
 mod event_model;
 mod keyboard_model;
+mod midi_export;
 mod midi_mapping;
 mod midi_translation;
 mod osc_model;
+mod patterns;
+mod quantization;
+mod shuttle;
+mod time_signature;
 mod util;
 
+mod cli;
 mod config;
+mod logging;
+mod metronome;
 mod midi_read_daemon;
 mod ncurses_daemon;
 mod osc_client;
+mod output;
+mod playback;
+mod recording;
+mod session;
 mod state;
 
 fn main() {
-    match run() {
+    let cli = match cli::parse(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if cli.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+
+    if cli.list_devices {
+        match midi_read_daemon::list_devices() {
+            Ok(devices) => devices.iter().for_each(|name| println!("{}", name)),
+            Err(err) => eprintln!("Error: {}", err),
+        }
+        return;
+    }
+
+    match run(cli) {
         Ok(_) => (),
-        Err(err) => println!("Error: {}", err),
+        Err(err) => eprintln!("Error: {}", err),
     }
 }
 
@@ -60,7 +104,7 @@ fn main() {
    - Shift-key history wipe
    - OSC-driven sample shifting
        - Requires that pads have a clear ID (ideally as written on board)
-       - Requires new message (old configuration used letter-to-index)
+       - DONE: /keyboard_letter_index (old configuration used letter-to-index)
    - ncurses notes display, like in old keyboard
        - A clear step towards front end
    - backend separation
@@ -68,7 +112,7 @@ fn main() {
        - Do as late as possible
 */
 
-fn run() -> Result<(), Box<dyn Error>> {
+fn run(cli: CliArgs) -> Result<(), Box<dyn Error>> {
     /*
 
         HEAPRB & STRUCTURING FOR REUSE WITH KEYBOARD
@@ -88,7 +132,11 @@ fn run() -> Result<(), Box<dyn Error>> {
 
     */
 
-    config::init(Some("config.toml"));
+    if let Err(err) = config::init(Some(&cli.config_path), cli.config_overrides()) {
+        eprintln!("Invalid config: {}", err);
+        std::process::exit(2);
+    }
+    logging::set_headless(cli.headless);
 
     // NOTE: I have no idea what an appropriate capacity is
     let midi_pipe = HeapRb::<MIDIEvent>::new(100);
@@ -99,6 +147,9 @@ fn run() -> Result<(), Box<dyn Error>> {
 
     let oscd_keycontrol = Arc::new(Mutex::new(keycontrol_pub));
 
+    let midi_pub = Arc::new(Mutex::new(midi_pub));
+    let oscd_midi_pub = midi_pub.clone();
+
     let (mut history_event_out, mut history_event_in) = HeapRb::<Event>::new(100).split();
 
     // State init
@@ -114,12 +165,53 @@ fn run() -> Result<(), Box<dyn Error>> {
     let hist_daemon_history = osc_read_history.clone();
 
     let ncurses_state = midi_read_state.clone();
+    let headless_state = midi_read_state.clone();
     let ncurses_history = midi_read_history.clone();
+    let session_state = midi_read_state.clone();
+    let session_history = midi_read_history.clone();
 
     let cfg = config::Config::get();
-    let socket = UdpSocket::bind(SocketAddrV4::from_str(
-        &format!("127.0.0.1:{}", cfg.local_bind_port)
-    ).unwrap()).unwrap();
+
+    if cfg.session_restore && !cfg.session_file.is_empty() {
+        let restored = session::load(Path::new(&cfg.session_file)).and_then(|session| {
+            session.apply(&mut midi_read_state.lock().unwrap(), &mut midi_read_history.lock().unwrap())
+        });
+
+        match restored {
+            Ok(_) => logging::log(format!("Restored session from {}", cfg.session_file)),
+            Err(e) => logging::log(format!("Could not restore session from {}: {}", cfg.session_file, e)),
+        }
+    }
+
+    // Session autosave thread; only writes when something changed
+    if !cfg.session_file.is_empty() && cfg.session_autosave_secs > 0 {
+        let autosave_state = midi_read_state.clone();
+        let autosave_history = midi_read_history.clone();
+
+        thread::spawn(move || {
+            let mut last_saved: Option<Session> = None;
+
+            loop {
+                sleep(Duration::from_secs(cfg.session_autosave_secs));
+
+                let session = capture_session(&autosave_state, &autosave_history);
+                if last_saved.as_ref() != Some(&session) {
+                    match session::save(Path::new(&cfg.session_file), &session) {
+                        Ok(_) => last_saved = Some(session),
+                        Err(e) => logging::log(format!("Session autosave failed: {}", e)),
+                    }
+                }
+            }
+        });
+    }
+    let socket = match UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, cfg.local_bind_port)) {
+        Ok(socket) => socket,
+        Err(e) => {
+            // Before the TUI starts, so straight to stderr; e.g. another instance on the same port
+            eprintln!("Could not bind local port {}: {} (see --port-offset)", cfg.local_bind_port, e);
+            std::process::exit(1);
+        }
+    };
 
     socket.set_nonblocking(true).unwrap();
     socket
@@ -129,17 +221,27 @@ fn run() -> Result<(), Box<dyn Error>> {
         .set_read_timeout(Some(Duration::from_millis(1)))
         .unwrap();
 
-    let mut client = OscClient::new(
+    let client = OscClient::new(
         socket,
         SocketAddrV4::from_str(
             &format!("{}:{}", cfg.router_host, cfg.router_port)
         ).unwrap(),
     );
 
+    let osc_reply_client = client.try_clone().unwrap();
+    let broadcast_client = client.try_clone().unwrap();
+    let broadcast_state = midi_read_state.clone();
+
+    let metronome_state = midi_read_state.clone();
+    let metronome_client = client.try_clone().unwrap();
+    thread::spawn(move || metronome::run(metronome_state, metronome_client));
+    let mut outputs = Outputs::from_config(cfg, client.try_clone().unwrap());
+
     // Subscribe to keyboard port on the router
+    // Keyboard addresses are namespaced per instance, while shared ones (bpm, sc events) are not
     let keyboard_port = cfg.osc_listen_port;
-    for addr in &[
-        "/set_bpm",
+    let shared_addresses = ["/set_bpm", "/jdw_sc_event"];
+    let instance_addresses = [
         "/keyboard_quantization",
         "/keyboard_octave",
         "/keyboard_args",
@@ -150,51 +252,103 @@ fn run() -> Result<(), Box<dyn Error>> {
         "/keyboard_mode_synth",
         "/keyboard_mode_sampler",
         "/keyboard_instrument_name",
-        "/jdw_sc_event",
-    ] {
-        client.send(osc_model::create_subscribe(addr, "127.0.0.1", keyboard_port));
+        "/keyboard_record",
+        "/keyboard_record_mode",
+        "/keyboard_punch_out",
+        "/keyboard_count_in",
+        "/keyboard_metronome",
+        "/keyboard_time_signature",
+        "/keyboard_loop_length",
+        "/keyboard_sustain_mode",
+        "/keyboard_quantize_mode",
+        "/keyboard_output_lane",
+        "/keyboard_export_midi",
+        "/keyboard_play_phrase",
+        "/keyboard_stop_phrase",
+        "/keyboard_undo",
+        "/keyboard_redo",
+        "/keyboard_get_state",
+        "/keyboard_play_note",
+        "/keyboard_release_note",
+        "/keyboard_hit_pad",
+    ];
+    for addr in shared_addresses
+        .iter()
+        .map(|addr| addr.to_string())
+        .chain(instance_addresses.iter().map(|addr| cfg.osc_address(addr)))
+    {
+        client.send(osc_model::create_subscribe(&addr, "127.0.0.1", keyboard_port));
     }
 
     // History stringify thread
     thread::spawn(move || {
+        // Whether the outputs currently hold a phrase
+        let mut published_notes = false;
+
         loop {
-            while let Some(event) = history_event_in.try_pop() {
-                hist_daemon_history.lock().unwrap().add(event);
-
-                let state_lock = hist_daemon_state.lock().unwrap();
-                let bpm = state_lock.bpm.clone();
-                let quantize = state_lock.quantize_enabled;
-                let quantization = if quantize {
-                    state_lock.quantization.clone()
-                } else {
-                    BigDecimal::from_str("0.001").unwrap()
+            // One lock for the whole batch, so OSC handlers waiting on history aren't held up per event
+            let modified = {
+                let mut history = hist_daemon_history.lock().unwrap();
+                while let Some(event) = history_event_in.try_pop() {
+                    history.add(event);
+                }
+
+                // New events, undo/redo, clears and loop starts all mark history as modified
+                std::mem::replace(&mut history.modified, false)
+            };
+
+            if modified {
+                // Settings are copied out first, so MIDI and playback threads aren't held up by rendering
+                let settings = hist_daemon_state.lock().unwrap().render_settings();
+                let (has_notes, lanes, stringified) = {
+                    let history_lock = hist_daemon_history.lock().unwrap();
+                    let lanes: Vec<String> = history_lock
+                        .lanes(cfg.lanes_per_instrument)
+                        .iter()
+                        .map(|lane| lane.name())
+                        .collect();
+                    (history_lock.has_notes(), lanes, settings.render(&history_lock))
                 };
-                drop(state_lock);
 
-                let sequence = hist_daemon_history
-                    .lock()
-                    .unwrap()
-                    .as_sequence(bpm, quantization.clone());
+                {
+                    let mut state_lock = hist_daemon_state.lock().unwrap();
+                    state_lock.lanes = lanes;
+                    state_lock.history_preview = stringified.clone();
+                    state_lock.history_revision += 1;
+                }
 
-                let ends_on_sample = hist_daemon_history.lock().unwrap().ends_on_sample();
+                // Clipboard, file, stdout and/or OSC, as configured.
+                // Empty output is only written once, so an undo or clear doesn't leave the undone phrase behind.
+                if has_notes || published_notes {
+                    outputs.publish(&stringified);
+                }
+                published_notes = has_notes;
+            }
 
-                let multiline = hist_daemon_state.lock().unwrap().multiline_output;
-                let stringified = event_history::stringify_history(sequence, ends_on_sample, multiline);
+            sleep(Duration::from_millis(200));
+        }
+    });
+
+    // State broadcast thread: publish any changed field, regardless of whether TUI, OSC or MIDI changed it
+    thread::spawn(move || {
+        let mut last_snapshot = broadcast_state.lock().unwrap().snapshot();
 
-                hist_daemon_state.lock().unwrap().history_preview = stringified.clone();
+        loop {
+            sleep(Duration::from_millis(50));
 
-                // Copy to clipboard
-                let opts = Options::new();
-                opts.copy(
-                    Source::Bytes(stringified.clone().into_bytes().into()),
-                    MimeType::Autodetect,
-                )
-                .unwrap();
+            let snapshot = broadcast_state.lock().unwrap().snapshot();
 
-                //println!("Had bpm {}, Copied to clipboard! {}", bpm, stringified);
+            for ((field, values), (_, old_values)) in snapshot.iter().zip(last_snapshot.iter()) {
+                if values != old_values {
+                    broadcast_client.send(osc_model::create_state_message(
+                        &cfg.osc_address("/keyboard_state_changed"),
+                        field,
+                        values.clone(),
+                    ));
+                }
             }
 
-            sleep(Duration::from_millis(200));
+            last_snapshot = snapshot;
         }
     });
 
@@ -215,7 +369,7 @@ fn run() -> Result<(), Box<dyn Error>> {
                 osc_read_state.lock().unwrap().set_bpm(bpm_arg)
             })
             .on_message(
-                "/keyboard_octave",
+                &cfg.osc_address("/keyboard_octave"),
                 &|msg| match msg.args.get(0).unwrap().clone().int() {
                     Some(octave) => oscd_keycontrol
                         .lock()
@@ -224,20 +378,18 @@ fn run() -> Result<(), Box<dyn Error>> {
                             octave: (octave - 1) as u8,
                         })
                         .unwrap_or_else(|_| {}),
-                    None => println!("Could not find any octave number in octave message"),
+                    None => logging::log("Could not find any octave number in octave message".to_string()),
                 },
             )
-            .on_message("/keyboard_quantization", &|msg| {
-                let quantization = msg.args.get(0).unwrap().clone().string().unwrap();
-                osc_read_state
-                    .lock()
-                    .unwrap()
-                    .set_quantization(&quantization);
+            .on_message(&cfg.osc_address("/keyboard_quantization"), &|msg| {
+                if let Err(e) = osc_read_state.lock().unwrap().quantization.apply_args(&msg.args) {
+                    logging::log(e);
+                }
             })
-            .on_message("/keyboard_args", &|msg| {
+            .on_message(&cfg.osc_address("/keyboard_args"), &|msg| {
                 osc_read_state.lock().unwrap().set_args(msg.args.clone());
             })
-            .on_message("/keyboard_pad_samples", &|msg| {
+            .on_message(&cfg.osc_address("/keyboard_pad_samples"), &|msg| {
                 // Iterate osc args in pairs
                 for w in msg.args.chunks(2) {
                     let pad_id = w[0].clone().int().unwrap() as u8;
@@ -252,20 +404,231 @@ fn run() -> Result<(), Box<dyn Error>> {
                         .unwrap();
                 }
             })
-            .on_message("/keyboard_pad_pack", &|msg| {
+            .on_message(&cfg.osc_address("/keyboard_letter_index"), &|msg| {
+                // Iterate osc args in pairs of <letter, sample index>
+                for w in msg.args.chunks(2) {
+                    let letter = w[0].clone().string().and_then(|s| s.chars().next());
+                    let sample_index = w.get(1).and_then(|a| a.clone().int());
+
+                    match (letter, sample_index) {
+                        (Some(letter), Some(index)) => {
+                            let known = osc_read_state
+                                .lock()
+                                .unwrap()
+                                .set_letter_index(letter.to_ascii_lowercase(), index);
+
+                            if !known {
+                                logging::log(format!("No pad is bound to letter {}", letter));
+                            }
+                        }
+                        _ => logging::log(format!("Malformed letter index pair: {:?}", w)),
+                    }
+                }
+            })
+            .on_message(&cfg.osc_address("/keyboard_mode_synth"), &|_msg| {
+                osc_read_state.lock().unwrap().keyboard_mode = KeyboardMode::Keyboard;
+            })
+            .on_message(&cfg.osc_address("/keyboard_mode_sampler"), &|_msg| {
+                osc_read_state.lock().unwrap().keyboard_mode = KeyboardMode::Sampler;
+            })
+            .on_message(&cfg.osc_address("/keyboard_pad_pack"), &|msg| {
                 let name = msg.args.get(0).cloned().unwrap().string().unwrap();
-                println!("CHANGING SAMPLER TO {}", name);
+                logging::log(format!("CHANGING SAMPLER TO {}", name));
 
                 osc_read_state.lock().unwrap().pads_configuration.pack_name = name;
             })
-            .on_message("/keyboard_pad_args", &|msg| {
+            .on_message(&cfg.osc_address("/keyboard_pad_args"), &|msg| {
                 osc_read_state.lock().unwrap().pads_configuration.args = msg.args.clone();
             })
-            .on_message("/keyboard_instrument_name", &|msg| {
+            .on_message(&cfg.osc_address("/keyboard_instrument_name"), &|msg| {
                 let name = msg.args.get(0).unwrap().clone().string().unwrap();
-                println!("CHANGING KEYBOARD TO {}", name);
+                logging::log(format!("CHANGING KEYBOARD TO {}", name));
                 osc_read_state.lock().unwrap().instrument_name = name;
             })
+            .on_message(&cfg.osc_address("/keyboard_record"), &|msg| {
+                match msg.args.get(0).and_then(|a| a.clone().int()) {
+                    Some(enabled) => osc_read_state.lock().unwrap().recorder.set_enabled(enabled != 0),
+                    None => logging::log("Could not find any on/off value in record message".to_string()),
+                }
+            })
+            .on_message(&cfg.osc_address("/keyboard_record_mode"), &|msg| {
+                let name = msg.args.get(0).and_then(|a| a.clone().string()).unwrap_or_default();
+                match RecordMode::from_name(&name) {
+                    Some(mode) => osc_read_state.lock().unwrap().recorder.set_mode(mode),
+                    None => logging::log(format!("Unknown record mode: {}", name)),
+                }
+            })
+            .on_message(&cfg.osc_address("/keyboard_punch_out"), &|msg| {
+                match msg.args.get(0).and_then(|a| a.clone().int()) {
+                    Some(loops) => {
+                        osc_read_state.lock().unwrap().recorder.punch_out_loops =
+                            Some(loops.max(0) as u32).filter(|loops| *loops > 0);
+                    }
+                    None => logging::log("Could not find any loop count in punch out message".to_string()),
+                }
+            })
+            .on_message(&cfg.osc_address("/keyboard_count_in"), &|msg| {
+                match msg.args.get(0).and_then(|a| a.clone().int()) {
+                    Some(bars) => osc_read_state.lock().unwrap().recorder.count_in_bars = bars.max(0) as u32,
+                    None => logging::log("Could not find any bar count in count in message".to_string()),
+                }
+            })
+            .on_message(&cfg.osc_address("/keyboard_metronome"), &|msg| {
+                match msg.args.get(0).and_then(|a| a.clone().int()) {
+                    Some(enabled) => osc_read_state.lock().unwrap().metronome_enabled = enabled != 0,
+                    None => logging::log("Could not find any on/off value in metronome message".to_string()),
+                }
+            })
+            .on_message(&cfg.osc_address("/keyboard_time_signature"), &|msg| {
+                let name = msg.args.get(0).and_then(|a| a.clone().string()).unwrap_or_default();
+                match TimeSignature::from_name(&name) {
+                    Some(time_signature) => {
+                        osc_read_state.lock().unwrap().time_signature = time_signature;
+                        // Loop lengths are counted in bars
+                        osc_read_history.lock().unwrap().modified = true;
+                    }
+                    None => logging::log(format!("Unknown time signature: {}", name)),
+                }
+            })
+            .on_message(&cfg.osc_address("/keyboard_loop_length"), &|msg| {
+                let name = msg.args.get(0).and_then(|a| a.clone().string()).unwrap_or_default();
+                let bars = msg.args.get(1).and_then(|a| a.clone().int()).unwrap_or(cfg.loop_length_bars as i32);
+                match LoopLength::from_name(&name, bars.max(0) as u32) {
+                    Some(loop_length) => {
+                        osc_read_state.lock().unwrap().loop_length = loop_length;
+                        osc_read_history.lock().unwrap().modified = true;
+                    }
+                    None => logging::log(format!("Unknown loop length: {}", name)),
+                }
+            })
+            .on_message(&cfg.osc_address("/keyboard_sustain_mode"), &|msg| {
+                let name = msg.args.get(0).and_then(|a| a.clone().string()).unwrap_or_default();
+                match SustainMode::from_name(&name) {
+                    Some(mode) => osc_read_state.lock().unwrap().sustain_mode = mode,
+                    None => logging::log(format!("Unknown sustain mode: {}", name)),
+                }
+            })
+            .on_message(&cfg.osc_address("/keyboard_quantize_mode"), &|msg| {
+                let name = msg.args.get(0).and_then(|a| a.clone().string()).unwrap_or_default();
+                match QuantizeMode::from_name(&name) {
+                    Some(mode) => osc_read_state.lock().unwrap().quantize_mode = mode,
+                    None => logging::log(format!("Unknown quantize mode: {}", name)),
+                }
+            })
+            .on_message(&cfg.osc_address("/keyboard_output_lane"), &|msg| {
+                match msg.args.get(0).and_then(|a| a.clone().string()) {
+                    Some(lane) => {
+                        osc_read_state.lock().unwrap().output_lane = lane;
+                        // Re-render the preview and clipboard for the new lane
+                        osc_read_history.lock().unwrap().modified = true;
+                    }
+                    None => logging::log("Could not find any lane name in output lane message".to_string()),
+                }
+            })
+            .on_message(&cfg.osc_address("/keyboard_export_midi"), &|msg| {
+                // Optional take numbers, counted from 1 as in the take browser
+                let take_number = |index: usize| msg.args.get(index).and_then(|a| a.clone().int());
+                let takes = take_number(0).map(|first| {
+                    let last = take_number(1).unwrap_or(first);
+                    ((first.max(1) - 1) as usize, (last.max(1) - 1) as usize)
+                });
+
+                let state = osc_read_state.lock().unwrap();
+                export_midi(&state, &osc_read_history.lock().unwrap(), takes);
+            })
+            .on_message(&cfg.osc_address("/keyboard_play_phrase"), &|msg| {
+                // ["clipboard", loop], ["file", loop, path, <marker>] or ["text", loop, notation]
+                let text_arg = |index: usize| msg.args.get(index).and_then(|a| a.clone().string());
+                let looping = match msg.args.get(1) {
+                    Some(OscType::Int(value)) => *value != 0,
+                    Some(OscType::Bool(value)) => *value,
+                    _ => false,
+                };
+
+                let source = match text_arg(0).as_deref() {
+                    Some("clipboard") => Some(PhraseSource::Clipboard),
+                    Some("file") => text_arg(2).map(|path| PhraseSource::File { path, marker: text_arg(3) }),
+                    Some("text") => text_arg(2).map(PhraseSource::Text),
+                    _ => None,
+                };
+
+                match source {
+                    Some(source) => {
+                        let _ = oscd_midi_pub
+                            .lock()
+                            .unwrap()
+                            .try_push(MIDIEvent::Command(NcursesCommand::PlayPhrase(source, looping)));
+                    }
+                    None => logging::log("Could not find any phrase source in play message".to_string()),
+                }
+            })
+            .on_message(&cfg.osc_address("/keyboard_stop_phrase"), &|_msg| {
+                let _ = oscd_midi_pub.lock().unwrap().try_push(MIDIEvent::Command(NcursesCommand::StopPhrase));
+            })
+            .on_message(&cfg.osc_address("/keyboard_undo"), &|msg| {
+                let scope_name = msg.args.get(0).and_then(|a| a.clone().string()).unwrap_or("note".to_string());
+                match UndoScope::from_name(&scope_name) {
+                    Some(scope) => osc_read_history.lock().unwrap().undo(scope),
+                    None => logging::log(format!("Unknown undo scope: {}", scope_name)),
+                }
+            })
+            .on_message(&cfg.osc_address("/keyboard_redo"), &|_msg| {
+                osc_read_history.lock().unwrap().redo();
+            })
+            .on_message(&cfg.osc_address("/keyboard_get_state"), &|_msg| {
+                let snapshot = osc_read_state.lock().unwrap().snapshot();
+                for (field, values) in snapshot {
+                    osc_reply_client.send(osc_model::create_state_message(
+                        &cfg.osc_address("/keyboard_state"),
+                        field,
+                        values,
+                    ));
+                }
+            })
+            .on_message(&cfg.osc_address("/keyboard_play_note"), &|msg| {
+                // Synthetic events go through the processor like any TUI/MIDI input
+                match msg.args.get(0).and_then(|a| a.clone().int()) {
+                    Some(note) => {
+                        let force = msg.args.get(1).and_then(|a| a.clone().int()).unwrap_or(127);
+                        let _ = oscd_midi_pub.lock().unwrap().try_push(MIDIEvent::Key(Key {
+                            pressed: true,
+                            midi_note: note.clamp(0, 127) as u8,
+                            force: force.clamp(0, 127) as u8,
+                        }));
+                    }
+                    None => logging::log("Could not find any note number in play message".to_string()),
+                }
+            })
+            .on_message(&cfg.osc_address("/keyboard_release_note"), &|msg| {
+                match msg.args.get(0).and_then(|a| a.clone().int()) {
+                    Some(note) => {
+                        let _ = oscd_midi_pub.lock().unwrap().try_push(MIDIEvent::Key(Key {
+                            pressed: false,
+                            midi_note: note.clamp(0, 127) as u8,
+                            force: 0,
+                        }));
+                    }
+                    None => logging::log("Could not find any note number in release message".to_string()),
+                }
+            })
+            .on_message(&cfg.osc_address("/keyboard_hit_pad"), &|msg| {
+                // Pads can be addressed by id or by their letter key
+                let pad_id = match msg.args.get(0).cloned() {
+                    Some(OscType::Int(id)) => Some(id.clamp(0, 127) as u8),
+                    Some(OscType::String(letter)) => letter.chars().next().and_then(pad_id_for_letter),
+                    _ => None,
+                };
+
+                match pad_id {
+                    Some(id) => {
+                        let _ = oscd_midi_pub.lock().unwrap().try_push(MIDIEvent::AbsPad(AbsPad {
+                            id,
+                            pressed: true,
+                        }));
+                    }
+                    None => logging::log("Could not find any pad in pad hit message".to_string()),
+                }
+            })
             .on_message("/set_available_instruments", &|msg| {
                 let instruments: Vec<String> = msg.args.iter()
                     .filter_map(|a| a.clone().string())
@@ -289,19 +652,37 @@ fn run() -> Result<(), Box<dyn Error>> {
                     .add(Event::Silence(Silence {
                         time: first_beat_plays_at,
                     }));
-                println!("Loop start registered");
+                logging::log("Loop start registered".to_string());
             })
             .on_message("/jdw_sc_event", &|msg| {
                 let event_name = msg.args.get(0).unwrap().clone().string().unwrap();
                 let timestamp = msg.args.get(1).unwrap().clone().time().unwrap();
                 let time = SystemTime::try_from(timestamp).unwrap();
                 // Yes, this is the only way to do it, because instant can only exist as a relative entity
-                let ins = Instant::now() + time.duration_since(SystemTime::now()).unwrap();
+                let now = SystemTime::now();
+                let ins = match time.duration_since(now) {
+                    Ok(ahead) => Instant::now() + ahead,
+                    // Already passed, e.g. when the message arrives late
+                    Err(_) => {
+                        let elapsed = now.duration_since(time).unwrap_or_default();
+                        Instant::now().checked_sub(elapsed).unwrap_or_else(Instant::now)
+                    }
+                };
 
-                println!("EVENT: {}", event_name);
+                logging::log(format!("EVENT: {}", event_name));
 
                 if (event_name == "loop_started".to_string()) {
-                    osc_read_history.lock().unwrap().register_beatbreak(ins);
+                    let mut state = osc_read_state.lock().unwrap();
+                    // The metronome restarts its bar from here
+                    state.last_loop_start_time = Some(ins);
+                    state.recorder.on_loop_start();
+                    let recording_active = state.recorder.is_active();
+                    drop(state);
+
+                    // Loop starts only matter to history while recording or armed
+                    if recording_active {
+                        osc_read_history.lock().unwrap().register_beatbreak(ins);
+                    }
                 }
             })
             .begin();
@@ -311,6 +692,9 @@ fn run() -> Result<(), Box<dyn Error>> {
 
     thread::spawn(move || {
         let mut last_played_pad: Option<u8> = None;
+        let mut player = Player::new();
+        // Notes whose NoteOn made it into history, so that their NoteOff follows even after recording stops
+        let mut recorded_notes: HashSet<u8> = HashSet::new();
         let mut idle_count: u32 = 0;
 
         loop {
@@ -342,32 +726,41 @@ fn run() -> Result<(), Box<dyn Error>> {
                             let msg = osc_model::create_note_on(
                                 key.midi_note as i32,
                                 instrument.as_str(),
-                                args,
+                                args.clone(),
                             );
 
                             client.send(msg);
 
-                            let _ = history_event_out
-                                .try_push(Event::NoteOn(NoteOn {
-                                    id: history_id,
-                                    time: read_time,
-                                    is_sample: false,
-                                }));
+                            if midi_read_state.lock().unwrap().recorder.on_note() {
+                                recorded_notes.insert(key.midi_note);
+
+                                let _ = history_event_out
+                                    .try_push(Event::NoteOn(NoteOn {
+                                        id: history_id,
+                                        time: read_time,
+                                        is_sample: false,
+                                        velocity: key.force,
+                                        instrument: instrument.clone(),
+                                        args,
+                                    }));
+                            }
                         } else {
                             let msg = osc_model::create_note_off(key.midi_note as i32);
 
                             client.send(msg);
 
-                            let _ = history_event_out
-                                .try_push(Event::NoteOff(NoteOff {
-                                    id: history_id,
-                                    time: read_time,
-                                }));
+                            if recorded_notes.remove(&key.midi_note) {
+                                let _ = history_event_out
+                                    .try_push(Event::NoteOff(NoteOff {
+                                        id: history_id,
+                                        time: read_time,
+                                    }));
+                            }
                         }
                     }
                     MIDIEvent::AbsPad(pad) => {
                         if pad.pressed {
-                            let state_read = midi_read_state.lock().unwrap();
+                            let mut state_read = midi_read_state.lock().unwrap();
 
                             let sample_index = state_read
                                 .pads_configuration
@@ -381,16 +774,22 @@ fn run() -> Result<(), Box<dyn Error>> {
                             let pad_args = state_read.pads_configuration.args.clone();
 
                             let msg =
-                                osc_model::create_play_sample(sample_index, &sample_pack, pad_args);
+                                osc_model::create_play_sample(sample_index, &sample_pack, pad_args.clone());
 
                             client.send(msg);
 
-                            let _ = history_event_out
-                                .try_push(Event::NoteOn(NoteOn {
-                                    id: sample_index.to_string(),
-                                    time: read_time,
-                                    is_sample: true,
-                                }));
+                            if state_read.recorder.on_note() {
+                                let _ = history_event_out
+                                    .try_push(Event::NoteOn(NoteOn {
+                                        id: sample_index.to_string(),
+                                        time: read_time,
+                                        is_sample: true,
+                                        // Pads carry no velocity
+                                        velocity: 127,
+                                        instrument: sample_pack.clone(),
+                                        args: pad_args,
+                                    }));
+                            }
 
                             last_played_pad = Some(pad.id);
 
@@ -402,7 +801,15 @@ fn run() -> Result<(), Box<dyn Error>> {
                         let value = util::midi_to_float(0.0..2.0, knob.value);
                         let msg = osc_model::create_control_bus_mod(knob.id as i32, value);
                         client.send(msg);
-                        println!("{:?}, {}", knob, value);
+                        logging::log(format!("{:?}, {}", knob, value));
+
+                        if cfg.record_controls && midi_read_state.lock().unwrap().recorder.is_recording() {
+                            let _ = history_event_out.try_push(Event::Control(Control {
+                                bus: knob.id as i32,
+                                value,
+                                time: read_time,
+                            }));
+                        }
                     }
                     MIDIEvent::KnobButton(button) => {
                         if let Some(pad) = last_played_pad {
@@ -418,7 +825,7 @@ fn run() -> Result<(), Box<dyn Error>> {
 
                                 let new_value = (existing_value + modifier).max(0);
 
-                                println!("Sample key changed to value: {}", new_value.clone());
+                                logging::log(format!("Sample key changed to value: {}", new_value.clone()));
                                 state.pads_configuration.pads.insert(pad, new_value);
 
                                 // Play the new configuration for easy browsing
@@ -444,6 +851,42 @@ fn run() -> Result<(), Box<dyn Error>> {
                             midi_read_history.lock().unwrap().clear();
                         }
                     }
+                    MIDIEvent::Command(NcursesCommand::Undo(scope)) => {
+                        midi_read_history.lock().unwrap().undo(scope);
+                    }
+                    MIDIEvent::Command(NcursesCommand::Redo) => {
+                        midi_read_history.lock().unwrap().redo();
+                    }
+                    MIDIEvent::Command(NcursesCommand::CopyTakes(first, last)) => {
+                        let settings = midi_read_state.lock().unwrap().render_settings();
+                        let takes = midi_read_history.lock().unwrap().take_range(first, last);
+                        match takes {
+                            Some(takes) => {
+                                if copy_to_clipboard(&settings.render(&takes)) {
+                                    logging::log(format!("Copied takes {} to {}", first + 1, last + 1));
+                                }
+                            }
+                            None => logging::log(format!("No takes {} to {} in history", first + 1, last + 1)),
+                        }
+                    }
+                    MIDIEvent::Command(NcursesCommand::ExportMidi(takes)) => {
+                        let state = midi_read_state.lock().unwrap();
+                        export_midi(&state, &midi_read_history.lock().unwrap(), takes);
+                    }
+                    MIDIEvent::Command(NcursesCommand::PlayPhrase(source, looping)) => {
+                        play_phrase(&mut player, &source, looping, &client, &midi_read_state);
+                    }
+                    MIDIEvent::Command(NcursesCommand::StopPhrase) => {
+                        player.stop();
+                    }
+                    MIDIEvent::Command(NcursesCommand::ToggleAudition) => {
+                        if player.is_playing() {
+                            player.stop();
+                            logging::log("Stopped playback".to_string());
+                        } else {
+                            play_phrase(&mut player, &PhraseSource::Clipboard, true, &client, &midi_read_state);
+                        }
+                    }
                     MIDIEvent::Command(cmd) => {
                         let mut state = midi_read_state.lock().unwrap();
                         match cmd {
@@ -453,15 +896,41 @@ fn run() -> Result<(), Box<dyn Error>> {
                                     KeyboardMode::Sampler => KeyboardMode::Keyboard,
                                 };
                             }
+                            NcursesCommand::ToggleMetronome => {
+                                state.metronome_enabled = !state.metronome_enabled;
+                            }
                             NcursesCommand::ToggleRecording => {
-                                state.record_history = !state.record_history;
+                                state.recorder.toggle();
+                            }
+                            NcursesCommand::CycleRecordMode => {
+                                let next = state.recorder.mode.next();
+                                state.recorder.set_mode(next);
                             }
                             NcursesCommand::ToggleQuantize => {
                                 state.quantize_enabled = !state.quantize_enabled;
                             }
+                            NcursesCommand::SetGrid(label) => {
+                                state.quantization.apply_preset(&label);
+                            }
+                            NcursesCommand::AdjustSwing(amount) => {
+                                state.quantization.adjust_swing(amount);
+                            }
+                            NcursesCommand::AdjustStrength(amount) => {
+                                state.quantization.adjust_strength(amount);
+                            }
+                            NcursesCommand::CycleOutputLane => {
+                                state.cycle_output_lane();
+                                midi_read_history.lock().unwrap().modified = true;
+                            }
+                            NcursesCommand::CycleQuantizeMode => {
+                                state.quantize_mode = state.quantize_mode.next();
+                            }
                             NcursesCommand::ToggleMultiline => {
                                 state.multiline_output = !state.multiline_output;
                             }
+                            NcursesCommand::CycleSustainMode => {
+                                state.sustain_mode = state.sustain_mode.next();
+                            }
                             NcursesCommand::CyclePadBank => {}
                             NcursesCommand::SetInstrument(name) => {
                                 state.instrument_name = name;
@@ -469,6 +938,13 @@ fn run() -> Result<(), Box<dyn Error>> {
                             NcursesCommand::SetPack(name) => {
                                 state.pads_configuration.pack_name = name;
                             }
+                            NcursesCommand::Undo(_)
+                            | NcursesCommand::Redo
+                            | NcursesCommand::CopyTakes(..)
+                            | NcursesCommand::ExportMidi(_)
+                            | NcursesCommand::PlayPhrase(..)
+                            | NcursesCommand::StopPhrase
+                            | NcursesCommand::ToggleAudition => {}
                         }
                     }
                     _ => {}
@@ -487,10 +963,135 @@ fn run() -> Result<(), Box<dyn Error>> {
         }
     });
 
-    //midi_read_daemon::begin(midi_pub)
-    NcursesDaemon::new(midi_pub, keycontrol_sub, ncurses_state, ncurses_history)
-        .begin()
-        .unwrap();
+    // Hardware MIDI input is optional; TUI and OSC input work without it
+    let _midi_connection = match midi_read_daemon::begin(midi_pub.clone(), &cfg.midi_device) {
+        Ok(connection) => Some(connection),
+        Err(err) => {
+            logging::log(format!("MIDI input unavailable: {}", err));
+            None
+        }
+    };
+
+    if cli.headless {
+        logging::log(format!(
+            "Running headless, listening for OSC on port {}",
+            cfg.osc_listen_port
+        ));
+
+        handle_stop_signals();
+
+        // No TUI to own the octave; keep shared state updated from OSC instead
+        while !STOP_REQUESTED.load(Ordering::SeqCst) {
+            while let Some(val) = keycontrol_sub.try_pop() {
+                headless_state.lock().unwrap().octave = val.octave;
+            }
+            sleep(Duration::from_millis(100));
+        }
+
+        logging::log("Stopping".to_string());
+    } else {
+        NcursesDaemon::new(midi_pub, keycontrol_sub, ncurses_state, ncurses_history)
+            .begin()
+            .unwrap();
+    }
+
+    if !cfg.session_file.is_empty() {
+        let session = capture_session(&session_state, &session_history);
+        if let Err(e) = session::save(Path::new(&cfg.session_file), &session) {
+            logging::log(format!("Could not save session to {}: {}", cfg.session_file, e));
+        }
+    }
     // TODO: Effectively no error handling whatsoever - should be streamlined
     Ok(())
 }
+
+// Set on SIGTERM or SIGINT, so a headless run can stop the way quitting the TUI does
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_stop(_signal: libc::c_int) {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+fn handle_stop_signals() {
+    let handler: extern "C" fn(libc::c_int) = request_stop;
+
+    // The handler only stores to an atomic, which is safe to do from a signal handler
+    unsafe {
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+}
+
+fn capture_session(state: &Arc<Mutex<State>>, history: &Arc<Mutex<EventHistory>>) -> Session {
+    let state = state.lock().unwrap();
+    let history = history.lock().unwrap();
+    Session::capture(&state, &history)
+}
+
+// Explicit copies go to the clipboard regardless of the configured output sinks
+fn copy_to_clipboard(text: &str) -> bool {
+    match output::clipboard().write(text) {
+        Ok(()) => true,
+        Err(e) => {
+            logging::log(format!("Warning: copy to clipboard failed: {}", e));
+            false
+        }
+    }
+}
+
+// Read and parse a phrase, then hand it to <player>; problems with the phrase are logged
+fn play_phrase(player: &mut Player, source: &PhraseSource, looping: bool, client: &OscClient, state: &Arc<Mutex<State>>) {
+    let cfg = config::Config::get();
+
+    let phrases = source
+        .read()
+        .map_err(|e| e.to_string())
+        .and_then(|text| shuttle::parse(&text, &cfg.sustain_legato_arg));
+
+    match phrases {
+        Ok(phrases) => {
+            logging::log(format!("Playing {} phrase(s){}", phrases.len(), if looping { ", looped" } else { "" }));
+            player.play(phrases, looping, client.try_clone().unwrap(), state.clone());
+        }
+        Err(e) => logging::log(format!("Could not play phrase: {}", e)),
+    }
+}
+
+// Write all of history, or a range of takes, as MIDI files to the configured export dir
+fn export_midi(state: &State, history: &EventHistory, takes: Option<(usize, usize)>) {
+    let cfg = config::Config::get();
+
+    let range;
+    let selection = match takes {
+        Some((first, last)) => match history.take_range(first, last) {
+            Some(takes) => {
+                range = takes;
+                &range
+            }
+            None => {
+                logging::log(format!("No takes {} to {} in history", first + 1, last + 1));
+                return;
+            }
+        },
+        None => history,
+    };
+
+    let options = ExportOptions {
+        bpm: state.bpm,
+        time_signature: state.time_signature,
+        quantization: Some(state.quantization.clone()).filter(|_| cfg.midi_export_quantized),
+        per_instrument: cfg.lanes_per_instrument,
+        drum_base_note: cfg.midi_export_drum_base,
+    };
+
+    let name = if cfg.instance_name.is_empty() { "keys".to_string() } else { cfg.instance_name.clone() };
+
+    match midi_export::export_files(selection, Path::new(&cfg.midi_export_dir), &name, &options) {
+        Ok(paths) => {
+            for path in paths {
+                logging::log(format!("Exported MIDI to {}", path.display()));
+            }
+        }
+        Err(e) => logging::log(format!("MIDI export failed: {}", e)),
+    }
+}
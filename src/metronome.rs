@@ -0,0 +1,98 @@
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use bigdecimal::ToPrimitive;
+use rosc::OscType;
+
+use crate::config::Config;
+use crate::osc_client::OscClient;
+use crate::osc_model;
+use crate::state::State;
+
+/*
+    Metronome clicks on every counted beat of the time signature, accented on the first beat of each bar.
+    The beat grid keeps running while the metronome is off, so count-ins and the recorder always see
+        downbeats. Loop starts from the sequencer (State.last_loop_start_time) restart the bar,
+        so clicks line up with the sequencer once it is running.
+*/
+
+// Longest sleep between checks for loop starts and tempo changes
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+pub fn run(state: Arc<Mutex<State>>, client: OscClient) {
+    let cfg = Config::get();
+
+    let mut next_click = Instant::now();
+    let mut beat_in_bar: u32 = 0;
+    let mut last_loop_start: Option<Instant> = None;
+    // Synth clicks are released on the next click
+    let mut sounding_note: Option<u8> = None;
+
+    loop {
+        let mut state_lock = state.lock().unwrap();
+
+        let beats_per_bar = state_lock.time_signature.beats;
+        let beat_seconds = state_lock.time_signature.beat_length().to_f64().unwrap_or(1.0) * 60.0
+            / state_lock.bpm.max(1) as f64;
+        let interval = Duration::from_secs_f64(beat_seconds);
+
+        if state_lock.last_loop_start_time != last_loop_start {
+            last_loop_start = state_lock.last_loop_start_time;
+
+            if let Some(loop_start) = last_loop_start {
+                // Catch up with loop starts reported late, keeping the beat count
+                next_click = loop_start;
+                beat_in_bar = 0;
+                while next_click + interval < Instant::now() {
+                    next_click += interval;
+                    beat_in_bar = (beat_in_bar + 1) % beats_per_bar;
+                }
+            }
+        }
+
+        let now = Instant::now();
+        if now < next_click {
+            drop(state_lock);
+            sleep((next_click - now).min(POLL_INTERVAL));
+            continue;
+        }
+
+        // E.g. after the machine was suspended; no burst of clicks to catch up
+        if now - next_click > interval {
+            next_click = now;
+        }
+
+        let downbeat = beat_in_bar == 0;
+        if downbeat {
+            state_lock.recorder.on_downbeat();
+        }
+
+        let audible = state_lock.metronome_enabled || state_lock.recorder.is_counting_in();
+        let instrument = state_lock.instrument_name.clone();
+        drop(state_lock);
+
+        if let Some(note) = sounding_note.take() {
+            client.send(osc_model::create_click_note_off(note as i32));
+        }
+
+        if audible {
+            let amp = if downbeat { cfg.metronome_accent_amp } else { cfg.metronome_amp };
+            let args = vec![OscType::String("amp".to_string()), OscType::Float(amp as f32)];
+
+            if cfg.metronome_sound == "synth" {
+                let note = if downbeat { cfg.metronome_accent_note } else { cfg.metronome_note };
+                // Empty means the instrument currently played
+                let synth = if cfg.metronome_synth.is_empty() { &instrument } else { &cfg.metronome_synth };
+                client.send(osc_model::create_click_note_on(note as i32, synth, args));
+                sounding_note = Some(note);
+            } else {
+                let sample = if downbeat { cfg.metronome_accent_sample } else { cfg.metronome_sample };
+                client.send(osc_model::create_play_sample(sample, &cfg.metronome_pack, args));
+            }
+        }
+
+        next_click += interval;
+        beat_in_bar = (beat_in_bar + 1) % beats_per_bar;
+    }
+}
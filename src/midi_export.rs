@@ -0,0 +1,280 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use bigdecimal::{BigDecimal, ToPrimitive, Zero};
+
+use crate::event_history::{EventHistory, Lane};
+use crate::event_model::{BeatBreak, Event};
+use crate::midi_translation;
+use crate::quantization::Quantization;
+use crate::time_signature::TimeSignature;
+use crate::util::duration_to_beats;
+
+/*
+    Standard MIDI File export of recorded history, for taking ideas into a DAW.
+    Written as format 1: a tempo track followed by one track per lane, pads on the drum channel.
+*/
+
+const TICKS_PER_BEAT: u16 = 480;
+const DRUM_CHANNEL: u8 = 9;
+// Length of notes that were never released, e.g. pad hits
+const DEFAULT_LENGTH_BEATS: &str = "0.25";
+
+pub struct ExportOptions {
+    pub bpm: i64,
+    pub time_signature: TimeSignature,
+    // Snap onsets and note ends to this grid, counted from the last beat break like grid mode notation
+    pub quantization: Option<Quantization>,
+    pub per_instrument: bool,
+    // Drum note of sample index 0; pads are written as <drum_base_note + sample index>
+    pub drum_base_note: u8,
+}
+
+// Write history to <dir>, plus a quantized copy if given. Returns the written paths.
+pub fn export_files(
+    history: &EventHistory,
+    dir: &Path,
+    name: &str,
+    options: &ExportOptions,
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    std::fs::create_dir_all(dir)?;
+
+    let stamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut written = Vec::new();
+
+    let raw_path = dir.join(format!("{}_{}.mid", name, stamp));
+    std::fs::write(&raw_path, export(history, &ExportOptions { quantization: None, ..*options }))?;
+    written.push(raw_path);
+
+    if options.quantization.is_some() {
+        let quantized_path = dir.join(format!("{}_{}_quantized.mid", name, stamp));
+        std::fs::write(&quantized_path, export(history, options))?;
+        written.push(quantized_path);
+    }
+
+    Ok(written)
+}
+
+pub fn export(history: &EventHistory, options: &ExportOptions) -> Vec<u8> {
+    let mut tracks = vec![tempo_track(options.bpm, options.time_signature)];
+
+    let synth_channels = (0u8..16).filter(|channel| *channel != DRUM_CHANNEL);
+    let mut synth_channels = synth_channels.cycle();
+
+    for lane in history.lanes(options.per_instrument) {
+        let channel = match lane {
+            Lane::Pads => DRUM_CHANNEL,
+            _ => synth_channels.next().unwrap(),
+        };
+
+        tracks.push(lane_track(&history.lane(&lane, options.per_instrument), &lane, channel, options));
+    }
+
+    let mut file = Vec::new();
+    file.extend_from_slice(b"MThd");
+    file.extend_from_slice(&6u32.to_be_bytes());
+    file.extend_from_slice(&1u16.to_be_bytes());
+    file.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+    file.extend_from_slice(&TICKS_PER_BEAT.to_be_bytes());
+
+    for track in tracks {
+        file.extend_from_slice(b"MTrk");
+        file.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        file.extend(track);
+    }
+
+    file
+}
+
+fn tempo_track(bpm: i64, time_signature: TimeSignature) -> Vec<u8> {
+    let micros_per_beat = (60_000_000 / bpm.max(1)) as u32;
+
+    let mut track = Vec::new();
+    // Tempo
+    track.extend_from_slice(&[0x00, 0xFF, 0x51, 0x03]);
+    track.extend_from_slice(&micros_per_beat.to_be_bytes()[1..]);
+    // Beats, unit as a power of two, a click per counted beat (24 clocks per quarter), 8 32nds per quarter
+    track.extend_from_slice(&[
+        0x00, 0xFF, 0x58, 0x04,
+        time_signature.beats.min(255) as u8,
+        time_signature.unit.trailing_zeros() as u8,
+        (96 / time_signature.unit) as u8,
+        0x08,
+    ]);
+    track.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+    track
+}
+
+fn lane_track(history: &EventHistory, lane: &Lane, channel: u8, options: &ExportOptions) -> Vec<u8> {
+    let origin = history.start_time().unwrap_or(Instant::now());
+    let default_length = BigDecimal::from_str(DEFAULT_LENGTH_BEATS).unwrap();
+
+    // Grid origins: the start of history and each beat break, at <time, beats from the start>
+    let mut anchors: Vec<(Instant, BigDecimal)> = vec![(origin, BigDecimal::zero())];
+    if let Some(quantization) = &options.quantization {
+        for event in history.events() {
+            if let Event::BeatBreak(BeatBreak { time }) = event {
+                let (anchor, at) = anchors.last().unwrap().clone();
+                let offset = quantization.snap_position(duration_to_beats(time.saturating_duration_since(anchor), options.bpm));
+                anchors.push((*time, at + offset));
+            }
+        }
+    }
+
+    // <tick, is note on, message>; releases sort before presses on the same tick
+    let mut messages: Vec<(u32, bool, [u8; 3])> = Vec::new();
+
+    for (note_on, held) in history.notes() {
+        let note = if note_on.is_sample {
+            note_on.id.parse::<u32>().ok().map(|index| (options.drum_base_note as u32 + index).min(127) as u8)
+        } else {
+            midi_translation::oletter_to_tone(&note_on.id).filter(|tone| *tone < 128)
+        };
+
+        let Some(note) = note else { continue };
+
+        let start = duration_to_beats(note_on.time.duration_since(origin), options.bpm);
+        let length = held
+            .map(|held| duration_to_beats(held, options.bpm))
+            .unwrap_or(default_length.clone());
+
+        let (start, end) = match &options.quantization {
+            Some(quantization) => {
+                let (anchor, at) = anchors.iter().rev().find(|(time, _)| *time <= note_on.time).unwrap_or(&anchors[0]);
+                let from_anchor = duration_to_beats(note_on.time.saturating_duration_since(*anchor), options.bpm);
+                let start_snapped = at.clone() + quantization.snap_position(from_anchor.clone());
+                let end_snapped = at.clone() + quantization.snap_position(from_anchor + length);
+                // Never let a note collapse to nothing
+                let end = end_snapped.max(start_snapped.clone() + quantization.grid_step());
+                (start_snapped, end)
+            }
+            None => (start.clone(), start + length),
+        };
+
+        let velocity = note_on.velocity.clamp(1, 127);
+        messages.push((to_ticks(&start), true, [0x90 | channel, note, velocity]));
+        messages.push((to_ticks(&end), false, [0x80 | channel, note, 0]));
+    }
+
+    messages.sort_by_key(|(tick, is_on, _)| (*tick, *is_on));
+
+    let mut track = Vec::new();
+
+    let name = lane.name();
+    track.extend_from_slice(&[0x00, 0xFF, 0x03]);
+    track.extend(variable_length(name.len() as u32));
+    track.extend_from_slice(name.as_bytes());
+
+    let mut last_tick = 0;
+    for (tick, _, message) in messages {
+        track.extend(variable_length(tick - last_tick));
+        track.extend_from_slice(&message);
+        last_tick = tick;
+    }
+
+    track.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+    track
+}
+
+fn to_ticks(beats: &BigDecimal) -> u32 {
+    (beats * BigDecimal::from(TICKS_PER_BEAT))
+        .round(0)
+        .to_u32()
+        .unwrap_or(0)
+}
+
+// Delta times are written 7 bits per byte, most significant first, with the high bit set on all but the last
+fn variable_length(value: u32) -> Vec<u8> {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut rest = value >> 7;
+
+    while rest > 0 {
+        bytes.insert(0, (rest & 0x7F) as u8 | 0x80);
+        rest >>= 7;
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event_model::{NoteOff, NoteOn};
+    use std::time::Duration;
+
+    #[test]
+    fn verify() {
+        assert_eq!(variable_length(0), vec![0x00]);
+        assert_eq!(variable_length(0x7F), vec![0x7F]);
+        assert_eq!(variable_length(0x80), vec![0x81, 0x00]);
+        assert_eq!(variable_length(480), vec![0x83, 0x60]);
+
+        let start = Instant::now();
+        let mut history = EventHistory::new();
+        history.add(Event::NoteOn(NoteOn {
+            id: "c5".to_string(),
+            time: start,
+            is_sample: false,
+            velocity: 100,
+            instrument: "aPad".to_string(),
+            args: Vec::new(),
+        }));
+        // Released a little over half a beat later at 120bpm
+        history.add(Event::NoteOff(NoteOff { id: "c5".to_string(), time: start + Duration::from_millis(260) }));
+
+        let options = ExportOptions {
+            bpm: 120,
+            time_signature: TimeSignature { beats: 6, unit: 8 },
+            quantization: Some(Quantization {
+                step: BigDecimal::from_str("0.5").unwrap(),
+                tuplet: 1,
+                swing: 50,
+                strength: 100,
+            }),
+            per_instrument: false,
+            drum_base_note: 36,
+        };
+
+        let file = export(&history, &options);
+        assert_eq!(&file[0..4], b"MThd");
+        // Format 1, tempo track plus the synth lane, 480 ticks per beat
+        assert_eq!(&file[8..14], &[0x00, 0x01, 0x00, 0x02, 0x01, 0xE0]);
+
+        // 6/8 with a click per eighth
+        let tempo_track = &file[14..14 + 8 + 19];
+        assert_eq!(&tempo_track[15..23], &[0x00, 0xFF, 0x58, 0x04, 0x06, 0x03, 0x0C, 0x08]);
+
+        let tone = midi_translation::oletter_to_tone("c5").unwrap();
+        let synth_track = &file[14 + 8 + 19..];
+        assert_eq!(&synth_track[..4], b"MTrk");
+        // Name, then the note on at tick 0 and its release snapped to half a beat
+        assert_eq!(
+            &synth_track[8..],
+            &[
+                0x00, 0xFF, 0x03, 0x05, b's', b'y', b'n', b't', b'h',
+                0x00, 0x90, tone, 100,
+                0x81, 0x70, 0x80, tone, 0,
+                0x00, 0xFF, 0x2F, 0x00,
+            ]
+        );
+
+        // A loop restart late in the first beat snaps to half a beat; the note just after it is counted from there
+        history.add(Event::BeatBreak(BeatBreak { time: start + Duration::from_millis(370) }));
+        history.add(Event::NoteOn(NoteOn { time: start + Duration::from_millis(490), ..history.notes()[0].0.clone() }));
+        history.add(Event::NoteOff(NoteOff { id: "c5".to_string(), time: start + Duration::from_millis(610) }));
+
+        let file = export(&history, &options);
+        assert_eq!(
+            &file[14 + 8 + 19 + 8 + 9..],
+            &[
+                0x00, 0x90, tone, 100,
+                0x81, 0x70, 0x80, tone, 0,
+                0x00, 0x90, tone, 100,
+                0x81, 0x70, 0x80, tone, 0,
+                0x00, 0xFF, 0x2F, 0x00,
+            ]
+        );
+    }
+}
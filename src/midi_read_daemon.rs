@@ -1,60 +1,59 @@
 use std::error::Error;
-use std::io::stdin;
-use std::sync::Arc;
-use crate::keyboard_model::MIDIEvent;
+use crate::keyboard_model::MIDIEventPublisher;
+use crate::logging;
 use crate::midi_mapping::map;
-use midir::{Ignore, MidiInput};
-use ringbuf::storage::Heap;
+use midir::{Ignore, MidiInput, MidiInputConnection};
 use ringbuf::traits::Producer;
-use ringbuf::wrap::caching::Caching;
-use ringbuf::{HeapRb, SharedRb};
 
-// Read MIDI from a predefined source and translate the input to an internal struct, published to the provided ringbuf
+pub fn list_devices() -> Result<Vec<String>, Box<dyn Error>> {
+    let midi_in = MidiInput::new("midir device listing")?;
+
+    let names = midi_in
+        .ports()
+        .iter()
+        .filter_map(|port| midi_in.port_name(port).ok())
+        .collect();
+
+    Ok(names)
+}
+
+/*
+    Read MIDI from the named source and translate the input to an internal struct, published to the provided ringbuf.
+    Reading stops when the returned connection is dropped, so the caller must keep it alive.
+*/
 pub fn begin(
-    mut publisher: Caching<Arc<SharedRb<Heap<MIDIEvent>>>, true, false>
-) -> Result<(), Box<dyn Error>> {
-        // Start reading MIDI
-
-        let mut input = String::new();
-
-        let mut midi_in = MidiInput::new("midir reading input")?;
-        midi_in.ignore(Ignore::None);
-    
-        let arturia_id = "Arturia MiniLab mkII";
-    
-        let arturia_port = midi_in
-            .ports()
-            .into_iter()
-            .find(|port| midi_in.port_name(port).unwrap().contains(arturia_id))
-            .expect("No Arturia MiniLab Keyboard found!");
-    
-        println!("\nOpening connection");
-        let in_port_name = midi_in.port_name(&arturia_port)?;
-    
-        // _conn_in needs to be a named parameter, because it needs to be kept alive until the end of the scope
-        let _conn_in = midi_in.connect(
-            &arturia_port,
-            "midir-read-input",
-            move |stamp, message, _| {
-    
-                if let Some(event) = map(message) {
-
-                    publisher.try_push(event).unwrap();
-    
-                }
-    
-            },
-            (),
-        )?;
-    
-        println!(
-            "Connection open, reading input from '{}' (press enter to exit) ...",
-            in_port_name
-        );
-    
-        input.clear();
-        stdin().read_line(&mut input)?; // wait for next enter key press
-    
-        println!("Closing connection");
-        Ok(())
-}
\ No newline at end of file
+    publisher: MIDIEventPublisher,
+    device_name: &str,
+) -> Result<MidiInputConnection<()>, Box<dyn Error>> {
+    let mut midi_in = MidiInput::new("midir reading input")?;
+    midi_in.ignore(Ignore::None);
+
+    let port = midi_in
+        .ports()
+        .into_iter()
+        .find(|port| {
+            midi_in
+                .port_name(port)
+                .map(|name| name.contains(device_name))
+                .unwrap_or(false)
+        })
+        .ok_or(format!("No MIDI device matching '{}' found", device_name))?;
+
+    let in_port_name = midi_in.port_name(&port)?;
+
+    let connection = midi_in.connect(
+        &port,
+        "midir-read-input",
+        move |_stamp, message, _| {
+            if let Some(event) = map(message) {
+                // A full pipe means the processor is stalled; dropping input beats panicking here
+                let _ = publisher.lock().unwrap().try_push(event);
+            }
+        },
+        (),
+    )?;
+
+    logging::log(format!("MIDI connection open, reading input from '{}'", in_port_name));
+
+    Ok(connection)
+}
@@ -18,6 +18,17 @@ pub fn tone_to_oletter(tone: u8) -> String {
 
 }
 
+// Reverse of tone_to_oletter, e.g. "db2" => 13
+pub fn oletter_to_tone(oletter: &str) -> Option<u8> {
+    let split = oletter.find(|c: char| c.is_ascii_digit())?;
+    let (letter, octave) = oletter.split_at(split);
+
+    let letter_index = NOTE_NAMES.iter().position(|name| *name == letter)? as u32;
+    let octave: u32 = octave.parse().ok()?;
+
+    u8::try_from((octave.checked_sub(1)? * NOTE_NAMES.len() as u32) + letter_index).ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -31,5 +42,12 @@ mod tests {
         assert_eq!(tone_to_oletter(12), "c2");
         assert_eq!(tone_to_oletter(16), "e2");
         assert_eq!(tone_to_oletter((12 * 4) + 9), "a5");
+
+        assert_eq!(oletter_to_tone("c1"), Some(0));
+        assert_eq!(oletter_to_tone("db2"), Some(13));
+        assert_eq!(oletter_to_tone("a5"), Some((12 * 4) + 9));
+        assert_eq!(oletter_to_tone(&tone_to_oletter(127)), Some(127));
+        assert_eq!(oletter_to_tone("h3"), None);
+        assert_eq!(oletter_to_tone("12"), None);
     }
 }
\ No newline at end of file
@@ -10,27 +10,33 @@ use ringbuf::wrap::caching::Caching;
 use ringbuf::SharedRb;
 
 use crate::config::Config;
-use crate::event_history::EventHistory;
+use crate::event_history::{EventHistory, UndoScope};
 use crate::keyboard_model::Key as KbKey;
-use crate::keyboard_model::{AbsPad, KnobButton, MIDIEvent, NcursesCommand, ShiftButton};
+use crate::keyboard_model::{AbsPad, KnobButton, MIDIEvent, MIDIEventPublisher, NcursesCommand, ShiftButton, pad_id_for_letter, PAD_KEYS};
+use crate::logging;
 use crate::midi_translation::tone_to_oletter;
+use crate::quantization::GRID_PRESETS;
+use crate::recording::RecordStatus;
 use crate::state::{KeyboardMode, State};
 
 const KEYBOARD_KEYS: [char; 17] = [
     'q', '2', 'w', '3', 'e', 'r', '5', 't', '6', 'y', '7', 'u', 'i', '9', 'o', '0', 'p',
 ];
 
-    const PAD_KEYS: [char; 16] = [
-        'a', 's', 'd', 'f', 'g', 'h', 'j', 'k', 'l', 'z', 'x', 'c', 'v', 'b', 'n', 'm',
-    ];
-
     const MOD_KEYS: [char; 2] = ['+', '-'];
 
     const MAX_LOG_ENTRIES: usize = 100;
 
+    const MAX_LISTED_TAKES: usize = 8;
+
+    // Percent per key press
+    const SWING_STEP: i32 = 2;
+    const STRENGTH_STEP: i32 = 10;
+
 enum EditTarget {
     Instrument,
     Pack,
+    Grid,
 }
 
 enum EditState {
@@ -40,6 +46,24 @@ enum EditState {
         items: Vec<String>,
         selected: usize,
     },
+    // Index into EventHistory::takes(), plus the other end of a marked range
+    TakeBrowser {
+        selected: usize,
+        range_start: Option<usize>,
+    },
+}
+
+// Rendered take browser selection, kept until the selection or history changes
+struct TakePreview {
+    bounds: (usize, usize),
+    revision: u64,
+    text: String,
+}
+
+// First and last take of the browser selection
+fn take_bounds(selected: usize, range_start: Option<usize>) -> (usize, usize) {
+    let other = range_start.unwrap_or(selected);
+    (selected.min(other), selected.max(other))
 }
 
 #[derive(Clone)]
@@ -48,15 +72,15 @@ pub struct KeyboardModeState {
 }
 
 pub struct NcursesDaemon {
-    publisher: Caching<Arc<SharedRb<Heap<MIDIEvent>>>, true, false>,
+    publisher: MIDIEventPublisher,
     state_sub: Caching<Arc<SharedRb<Heap<KeyboardModeState>>>, false, true>,
     state: Arc<Mutex<State>>,
-    _history: Arc<Mutex<EventHistory>>,
+    history: Arc<Mutex<EventHistory>>,
 }
 
 impl NcursesDaemon {
     pub fn new(
-        publisher: Caching<Arc<SharedRb<Heap<MIDIEvent>>>, true, false>,
+        publisher: MIDIEventPublisher,
         state_sub: Caching<Arc<SharedRb<Heap<KeyboardModeState>>>, false, true>,
         state: Arc<Mutex<State>>,
         history: Arc<Mutex<EventHistory>>,
@@ -65,40 +89,57 @@ impl NcursesDaemon {
             publisher,
             state_sub,
             state,
-            _history: history,
+            history,
         }
     }
 
-    fn build_ui(&self, curr_octave: u8, pressed_keys: &HashSet<char>, pressed_pads: &HashSet<char>, event_log: &VecDeque<String>, edit_state: &Option<EditState>) -> String {
+    fn build_ui(&self, curr_octave: u8, pressed_keys: &HashSet<char>, pressed_pads: &HashSet<char>, event_log: &VecDeque<String>, edit_state: &Option<EditState>, take_preview: &mut Option<TakePreview>) -> String {
         let shared = self.state.lock().unwrap();
         let bpm = shared.bpm;
-        let quant = shared.quantization.to_string();
+        let quant = shared.quantization.label();
+        let quant_mode = shared.quantize_mode;
         let instrument = shared.instrument_name.clone();
         let mode = shared.keyboard_mode;
-        let recording = shared.record_history;
+        let record_status = shared.recorder.status;
+        let record_mode = shared.recorder.mode;
+        let punch_out = shared.recorder.punch_out_loops;
         let pack = shared.pads_configuration.pack_name.clone();
         let history_preview = shared.history_preview.clone();
+        let history_revision = shared.history_revision;
+        let sustain_mode = shared.sustain_mode;
+        let output_lane = shared.output_lane.clone();
+        let time_signature = shared.time_signature.name();
+        let metronome = if shared.metronome_enabled { "on" } else { "off" };
         drop(shared);
 
         let mode_label = match mode {
             KeyboardMode::Keyboard => "KEYBOARD",
             KeyboardMode::Sampler => "SAMPLER",
         };
-        let rec_dot = if recording { "●" } else { "○" };
+        let rec_label = match record_status {
+            RecordStatus::Recording => "● Recording",
+            RecordStatus::Armed => "◐ Armed",
+            RecordStatus::CountIn => "◔ Count-in",
+            RecordStatus::Off => "○ Recording",
+        };
+        let punch_label = punch_out.map(|loops| format!("  Punch-out: {} loops", loops)).unwrap_or_default();
 
         let mut ui = String::new();
 
-        let _ = writeln!(ui, "jdw-keys-backend v0.1          Router: 127.0.0.1:13339");
+        let cfg = Config::get();
+        let instance = if cfg.instance_name.is_empty() { String::new() } else { format!(" [{}]", cfg.instance_name) };
+        let _ = writeln!(ui, "jdw-keys-backend v0.1{}          Router: {}:{}", instance, cfg.router_host, cfg.router_port);
         let _ = writeln!(ui, "{}", "-".repeat(78));
         let _ = writeln!(ui);
         let base_note = tone_to_oletter(curr_octave * 12);
-        let _ = writeln!(ui, "  Octave: {} ({})    BPM: {}    Quant: {}", curr_octave, base_note, bpm, quant);
+        let _ = writeln!(ui, "  Octave: {} ({})    BPM: {} ({})    Quant: {} ({})    Metronome: {}", curr_octave, base_note, bpm, time_signature, quant, quant_mode.name(), metronome);
         if let Some(EditState::TextEdit(buf)) = edit_state {
             let _ = writeln!(ui, "  Instrument\u{2192} {}_    Pack: {}", buf, pack);
         } else if let Some(EditState::Dropdown { target, items, selected, .. }) = edit_state {
             let label = match target {
                 EditTarget::Instrument => "Instrument",
                 EditTarget::Pack => "Pack",
+                EditTarget::Grid => "Grid",
             };
             let _ = writeln!(ui, "  {}: {}    {}", label, items[*selected], if matches!(target, EditTarget::Instrument) { format!("Pack: {}", pack) } else { String::new() });
             for (i, item) in items.iter().enumerate() {
@@ -111,7 +152,7 @@ impl NcursesDaemon {
         } else {
             let _ = writeln!(ui, "  Instrument: {}    Pack: {}", instrument, pack);
         }
-        let _ = writeln!(ui, "  Mode: [{}]   {} Recording", mode_label, rec_dot);
+        let _ = writeln!(ui, "  Mode: [{}]   {} ({}){}", mode_label, rec_label, record_mode.name(), punch_label);
         let _ = writeln!(ui);
 
         // Keyboard row 1 (white keys)
@@ -159,9 +200,36 @@ impl NcursesDaemon {
         let _ = writeln!(ui);
         let _ = writeln!(ui);
 
-        // History
-        let _ = writeln!(ui, "  HISTORY:");
-        let _ = writeln!(ui, "  {}", history_preview);
+        // History, or the takes in it while browsing
+        if let Some(EditState::TakeBrowser { selected, range_start }) = edit_state {
+            let (first, last) = take_bounds(*selected, *range_start);
+            let stale = take_preview
+                .as_ref()
+                .is_none_or(|preview| preview.bounds != (first, last) || preview.revision != history_revision);
+            if stale {
+                let settings = self.state.lock().unwrap().render_settings();
+                let history = self.history.lock().unwrap();
+                let text = history
+                    .take_range(first, last)
+                    .map(|takes| settings.render(&takes))
+                    .unwrap_or_default();
+                *take_preview = Some(TakePreview { bounds: (first, last), revision: history_revision, text });
+            }
+            let preview = take_preview.as_ref().map(|preview| preview.text.as_str()).unwrap_or_default();
+            let takes = self.history.lock().unwrap().takes();
+
+            let _ = writeln!(ui, "  TAKES:  ({} recorded, lane: {})", takes.len(), output_lane);
+            let window_start = selected.saturating_sub(MAX_LISTED_TAKES / 2);
+            for (index, take) in takes.iter().enumerate().skip(window_start).take(MAX_LISTED_TAKES) {
+                let cursor = if index == *selected { "\u{25b6}" } else { " " };
+                let marker = if index >= first && index <= last { "*" } else { " " };
+                let _ = writeln!(ui, "    {}{} Take {}  ({} notes)", cursor, marker, index + 1, take.notes);
+            }
+            let _ = writeln!(ui, "  {}", preview);
+        } else {
+            let _ = writeln!(ui, "  HISTORY:  (lane: {}, sus: {})", output_lane, sustain_mode.name());
+            let _ = writeln!(ui, "  {}", history_preview);
+        }
         let _ = writeln!(ui);
 
         // Event log
@@ -178,8 +246,10 @@ impl NcursesDaemon {
             let _ = writeln!(ui, "  ESC:Cancel  Enter:Confirm");
         } else if matches!(edit_state, Some(EditState::Dropdown { .. })) {
             let _ = writeln!(ui, "  \u{2191}\u{2193}:Navigate  Enter:Select  Esc:Cancel");
+        } else if matches!(edit_state, Some(EditState::TakeBrowser { .. })) {
+            let _ = writeln!(ui, "  \u{2191}\u{2193}:Navigate  Space:Mark range  Enter:Copy  E:Export MIDI  Esc:Close");
         } else {
-            let _ = writeln!(ui, "  F2:Mode  F3:Record  F4:Quantize  F5:Multi  F6:Pack  F7:Inst  F8:-Oct  F9:+Oct  S+Enter:Clear  F10:Quit");
+            let _ = writeln!(ui, "  F2:Mode  S+F2:Metronome  F3:Record  S+F3:RecMode  F4:Quantize  S+F4:QuantMode  F5:Multi  S+F5:Sus  F6:Pack  F7:Inst  F8:-Oct  F9:+Oct  F11:Grid  S+F11:Play clipboard  [/]:Swing  ,/.:Strength  S+Enter:Clear  Bksp:Undo  S+Bksp:UndoTake  Del:Redo  Tab:Lane  F12:Takes  S+F12:Export MIDI  F10:Quit");
         }
 
         ui
@@ -198,6 +268,7 @@ impl NcursesDaemon {
         let mut last_render = Instant::now();
         let render_interval = Duration::from_millis(33);
         let mut edit_state: Option<EditState> = None;
+        let mut take_preview: Option<TakePreview> = None;
         let mut idle_count: u32 = 0;
 
         loop {
@@ -209,7 +280,19 @@ impl NcursesDaemon {
                     curr_octave = val.octave;
                 }
 
-                let ui = self.build_ui(curr_octave, &pressed_keys, &pressed_pads, &event_log, &edit_state);
+                event_log.extend(logging::drain());
+                while event_log.len() > MAX_LOG_ENTRIES {
+                    event_log.pop_front();
+                }
+
+                // Octave is owned here; mirror it to shared state so it can be queried/broadcast
+                let mut shared = self.state.lock().unwrap();
+                if shared.octave != curr_octave {
+                    shared.octave = curr_octave;
+                }
+                drop(shared);
+
+                let ui = self.build_ui(curr_octave, &pressed_keys, &pressed_pads, &event_log, &edit_state, &mut take_preview);
                 plane.erase();
                 plane.putstr(&ui)?;
                 plane.render()?;
@@ -231,7 +314,7 @@ impl NcursesDaemon {
                                     buffer.pop();
                                 } else if event.is_key(Key::Enter) {
                                     let name = buffer.clone();
-                                    let _ = self.publisher.try_push(MIDIEvent::Command(NcursesCommand::SetInstrument(name)));
+                                    let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::Command(NcursesCommand::SetInstrument(name)));
                                     edit_state = None;
                                 } else if event.is_key(Key::Esc) || event.is_key(Key::F01) {
                                     edit_state = None;
@@ -243,8 +326,9 @@ impl NcursesDaemon {
                                     let cmd = match target {
                                         EditTarget::Instrument => NcursesCommand::SetInstrument(name),
                                         EditTarget::Pack => NcursesCommand::SetPack(name),
+                                        EditTarget::Grid => NcursesCommand::SetGrid(name),
                                     };
-                                    let _ = self.publisher.try_push(MIDIEvent::Command(cmd));
+                                    let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::Command(cmd));
                                     edit_state = None;
                                 } else if event.is_key(Key::Esc) || event.is_key(Key::F01) {
                                     edit_state = None;
@@ -254,15 +338,42 @@ impl NcursesDaemon {
                                     *selected += 1;
                                 }
                             }
+                            EditState::TakeBrowser { selected, range_start } => {
+                                let take_count = self.history.lock().unwrap().takes().len();
+
+                                if event.is_key(Key::Enter) {
+                                    if take_count > 0 {
+                                        let (first, last) = take_bounds(*selected, *range_start);
+                                        let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::Command(NcursesCommand::CopyTakes(first, last)));
+                                    }
+                                    edit_state = None;
+                                } else if event.is_key(Key::Esc) || event.is_key(Key::F01) || event.is_key(Key::F12) {
+                                    edit_state = None;
+                                } else if event.is_char('e') && event.is_press() {
+                                    if take_count > 0 {
+                                        let (first, last) = take_bounds(*selected, *range_start);
+                                        let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::Command(NcursesCommand::ExportMidi(Some((first, last)))));
+                                    }
+                                } else if event.is_char(' ') && event.is_press() {
+                                    *range_start = match range_start {
+                                        Some(_) => None,
+                                        None => Some(*selected),
+                                    };
+                                } else if event.is_key(Key::Up) && *selected > 0 {
+                                    *selected -= 1;
+                                } else if event.is_key(Key::Down) && *selected + 1 < take_count {
+                                    *selected += 1;
+                                }
+                            }
                         }
                     } else {
                         for &pad_key in &PAD_KEYS {
                             if event.is_char(pad_key) {
                                 if event.is_press() {
                                     pressed_pads.insert(pad_key);
-                                    let pad_id = PAD_KEYS.iter().position(|&e| e == pad_key).unwrap() as u8 + 1;
+                                    let pad_id = pad_id_for_letter(pad_key).unwrap();
                                     event_log.push_back(format!("PadHit  pad:{}", pad_id));
-                                    let _ = self.publisher.try_push(MIDIEvent::AbsPad(AbsPad {
+                                    let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::AbsPad(AbsPad {
                                         id: pad_id,
                                         pressed: true,
                                     }));
@@ -278,7 +389,7 @@ impl NcursesDaemon {
                                 let midi_note = (curr_octave * 12u8) + midi_note_raw;
                                 pressed_keys.remove(&char_key);
                                 event_log.push_back(format!("NoteOff {}", tone_to_oletter(midi_note)));
-                                let _ = self.publisher.try_push(MIDIEvent::Key(KbKey {
+                                let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::Key(KbKey {
                                     pressed: false,
                                     midi_note,
                                     force: 127,
@@ -296,13 +407,13 @@ impl NcursesDaemon {
                                 if is_sampler {
                                     let pad_id = midi_note_raw + 1;
                                     event_log.push_back(format!("PadHit  pad:{}", pad_id));
-                                    let _ = self.publisher.try_push(MIDIEvent::AbsPad(AbsPad {
+                                    let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::AbsPad(AbsPad {
                                         id: pad_id,
                                         pressed: true,
                                     }));
                                 } else {
                                     event_log.push_back(format!("NoteOn  {}  vel:127", tone_to_oletter(midi_note)));
-                                    let _ = self.publisher.try_push(MIDIEvent::Key(KbKey {
+                                    let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::Key(KbKey {
                                         pressed: true,
                                         midi_note,
                                         force: 127,
@@ -321,7 +432,7 @@ impl NcursesDaemon {
                                     }
                                 } else {
                                     let emulated_knob_id = if event.is_char('+') { 113 } else { 115 };
-                                    let _ = self.publisher.try_push(MIDIEvent::KnobButton(KnobButton {
+                                    let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::KnobButton(KnobButton {
                                         id: emulated_knob_id,
                                         pressed: true,
                                     }));
@@ -329,8 +440,21 @@ impl NcursesDaemon {
                             }
                         }
 
+                        if event.is_key(Key::Backspace) && event.is_press() {
+                            let scope = if shift_pressed { UndoScope::Take } else { UndoScope::Note };
+                            let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::Command(NcursesCommand::Undo(scope)));
+                        }
+
+                        if event.is_key(Key::Tab) && event.is_press() {
+                            let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::Command(NcursesCommand::CycleOutputLane));
+                        }
+
+                        if event.is_key(Key::Del) && event.is_press() {
+                            let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::Command(NcursesCommand::Redo));
+                        }
+
                         if event.is_key(Key::Enter) {
-                            let _ = self.publisher.try_push(MIDIEvent::ShiftButton(ShiftButton {
+                            let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::ShiftButton(ShiftButton {
                                 pressed: true,
                             }));
                         }
@@ -348,19 +472,23 @@ impl NcursesDaemon {
                         }
 
                         if event.is_key(Key::F02) {
-                            let _ = self.publisher.try_push(MIDIEvent::Command(NcursesCommand::ToggleMode));
+                            let cmd = if shift_pressed { NcursesCommand::ToggleMetronome } else { NcursesCommand::ToggleMode };
+                            let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::Command(cmd));
                         }
 
                         if event.is_key(Key::F03) {
-                            let _ = self.publisher.try_push(MIDIEvent::Command(NcursesCommand::ToggleRecording));
+                            let cmd = if shift_pressed { NcursesCommand::CycleRecordMode } else { NcursesCommand::ToggleRecording };
+                            let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::Command(cmd));
                         }
 
                         if event.is_key(Key::F04) {
-                            let _ = self.publisher.try_push(MIDIEvent::Command(NcursesCommand::ToggleQuantize));
+                            let cmd = if shift_pressed { NcursesCommand::CycleQuantizeMode } else { NcursesCommand::ToggleQuantize };
+                            let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::Command(cmd));
                         }
 
                         if event.is_key(Key::F05) {
-                            let _ = self.publisher.try_push(MIDIEvent::Command(NcursesCommand::ToggleMultiline));
+                            let cmd = if shift_pressed { NcursesCommand::CycleSustainMode } else { NcursesCommand::ToggleMultiline };
+                            let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::Command(cmd));
                         }
 
                         if event.is_key(Key::F07) && edit_state.is_none() {
@@ -387,6 +515,40 @@ impl NcursesDaemon {
                             }
                         }
 
+                        if event.is_key(Key::F12) && shift_pressed {
+                            let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::Command(NcursesCommand::ExportMidi(None)));
+                        } else if event.is_key(Key::F12) && edit_state.is_none() {
+                            // Start at the latest take
+                            let take_count = self.history.lock().unwrap().takes().len();
+                            edit_state = Some(EditState::TakeBrowser { selected: take_count.saturating_sub(1), range_start: None });
+                        }
+
+                        if event.is_key(Key::F11) && shift_pressed {
+                            let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::Command(NcursesCommand::ToggleAudition));
+                        } else if event.is_key(Key::F11) && edit_state.is_none() {
+                            let items: Vec<String> = GRID_PRESETS.iter().map(|(label, _, _)| label.to_string()).collect();
+                            let shared = self.state.lock().unwrap();
+                            let selected = GRID_PRESETS
+                                .iter()
+                                .position(|(_, step, tuplet)| {
+                                    shared.quantization.step.to_string() == *step && shared.quantization.tuplet == *tuplet
+                                })
+                                .unwrap_or(0);
+                            drop(shared);
+                            edit_state = Some(EditState::Dropdown { target: EditTarget::Grid, items, selected });
+                        }
+
+                        for (key, cmd) in [
+                            ('[', NcursesCommand::AdjustSwing(-SWING_STEP)),
+                            (']', NcursesCommand::AdjustSwing(SWING_STEP)),
+                            (',', NcursesCommand::AdjustStrength(-STRENGTH_STEP)),
+                            ('.', NcursesCommand::AdjustStrength(STRENGTH_STEP)),
+                        ] {
+                            if event.is_char(key) && event.is_press() {
+                                let _ = self.publisher.lock().unwrap().try_push(MIDIEvent::Command(cmd));
+                            }
+                        }
+
                         if event.is_key(Key::F08) {
                             curr_octave = curr_octave.saturating_sub(1);
                         }
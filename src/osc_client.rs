@@ -16,7 +16,15 @@ impl OscClient {
         }
     }
 
-    pub fn send(&mut self, packet: OscPacket) {
+    // Separate handle on the same socket, for threads other than the MIDI processor
+    pub fn try_clone(&self) -> std::io::Result<OscClient> {
+        Ok(OscClient {
+            socket: self.socket.try_clone()?,
+            target_addr: self.target_addr,
+        })
+    }
+
+    pub fn send(&self, packet: OscPacket) {
         let msg_buf = encoder::encode(&packet).unwrap();
         self.socket.send_to(&msg_buf, self.target_addr).unwrap();
     }
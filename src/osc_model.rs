@@ -1,6 +1,8 @@
 use bigdecimal::ToPrimitive;
 use rosc::{OscMessage, OscPacket, OscType};
 
+use crate::config::Config;
+
 pub fn create_control_bus_mod(bus: i32, value: f32) -> OscPacket {
     OscPacket::Message(OscMessage {
         addr: "/c_set".to_string(),
@@ -10,7 +12,7 @@ pub fn create_control_bus_mod(bus: i32, value: f32) -> OscPacket {
 
 pub fn create_play_sample(index: i32, pack_name: &str, args: Vec<OscType>) -> OscPacket {
     let mut base_args = vec![
-        OscType::String("letter_sample_{nodeId}".to_string()), // ext_id
+        OscType::String(Config::get().external_id_prefix() + "letter_sample_{nodeId}"), // ext_id
         OscType::String(pack_name.to_string()),
         OscType::Int(index),
         OscType::String("".to_string()),
@@ -26,7 +28,32 @@ pub fn create_play_sample(index: i32, pack_name: &str, args: Vec<OscType>) -> Os
 }
 
 pub fn create_note_on(index: i32, synth_name: &str, args: Vec<OscType>) -> OscPacket {
-    let external_id = "letter_note_".to_string() + index.to_string().as_str() + "_{nodeId}";
+    note_on_message("letter_note_", index, synth_name, args)
+}
+
+pub fn create_note_off(index: i32) -> OscPacket {
+    note_off_message("letter_note_", index)
+}
+
+// Phrase playback and the metronome have ids of their own, so their releases never cut off a held key
+pub fn create_phrase_note_on(index: i32, synth_name: &str, args: Vec<OscType>) -> OscPacket {
+    note_on_message("phrase_note_", index, synth_name, args)
+}
+
+pub fn create_phrase_note_off(index: i32) -> OscPacket {
+    note_off_message("phrase_note_", index)
+}
+
+pub fn create_click_note_on(index: i32, synth_name: &str, args: Vec<OscType>) -> OscPacket {
+    note_on_message("metronome_note_", index, synth_name, args)
+}
+
+pub fn create_click_note_off(index: i32) -> OscPacket {
+    note_off_message("metronome_note_", index)
+}
+
+fn note_on_message(id_kind: &str, index: i32, synth_name: &str, args: Vec<OscType>) -> OscPacket {
+    let external_id = Config::get().external_id_prefix() + id_kind + index.to_string().as_str() + "_{nodeId}";
 
     let freq = psg::math::midi_pitch_to_frequency(index.to_f64().unwrap());
 
@@ -46,11 +73,11 @@ pub fn create_note_on(index: i32, synth_name: &str, args: Vec<OscType>) -> OscPa
     })
 }
 
-pub fn create_note_off(index: i32) -> OscPacket {
+fn note_off_message(id_kind: &str, index: i32) -> OscPacket {
     OscPacket::Message(OscMessage {
         addr: "/note_modify".to_string(),
         args: vec![
-            OscType::String("letter_note_".to_string() + index.to_string().as_str() + "_(.*)"),
+            OscType::String(Config::get().external_id_prefix() + id_kind + index.to_string().as_str() + "_(.*)"),
             OscType::Int(0),
             OscType::String("gate".to_string()),
             OscType::Float(0.0),
@@ -68,3 +95,21 @@ pub fn create_subscribe(osc_address: &str, ip: &str, port: u16) -> OscPacket {
         ],
     })
 }
+
+// Shared shape for state replies and change broadcasts: [field_name, ...values]
+pub fn create_state_message(osc_address: &str, field: &str, values: Vec<OscType>) -> OscPacket {
+    let mut args = vec![OscType::String(field.to_string())];
+    args.extend(values);
+
+    OscPacket::Message(OscMessage {
+        addr: osc_address.to_string(),
+        args,
+    })
+}
+
+pub fn create_history_string_message(osc_address: &str, history: &str) -> OscPacket {
+    OscPacket::Message(OscMessage {
+        addr: osc_address.to_string(),
+        args: vec![OscType::String(history.to_string())],
+    })
+}
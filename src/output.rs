@@ -0,0 +1,361 @@
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use itertools::Itertools;
+use wl_clipboard_rs::copy::{MimeType, Options, Source};
+use wl_clipboard_rs::paste::{get_contents, ClipboardType, MimeType as PasteMimeType, Seat};
+
+use crate::config::Config;
+use crate::logging;
+use crate::osc_client::OscClient;
+use crate::osc_model;
+use crate::util;
+
+/*
+    Destinations for stringified history. Several can be active at once (config: output_sinks).
+    A failing sink is reported as a warning and never stops the others, or the history thread.
+*/
+
+pub trait OutputSink: Send {
+    fn name(&self) -> &str;
+    fn write(&mut self, text: &str) -> Result<(), Box<dyn Error>>;
+}
+
+pub struct WaylandClipboard;
+
+impl OutputSink for WaylandClipboard {
+    fn name(&self) -> &str {
+        "wayland"
+    }
+
+    fn write(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+        Options::new().copy(Source::Bytes(text.as_bytes().into()), MimeType::Autodetect)?;
+        Ok(())
+    }
+}
+
+// Pipes to xclip, which has to be installed
+pub struct X11Clipboard;
+
+impl OutputSink for X11Clipboard {
+    fn name(&self) -> &str {
+        "x11"
+    }
+
+    fn write(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+        let mut xclip = Command::new("xclip")
+            .args(["-selection", "clipboard"])
+            .stdin(Stdio::piped())
+            .spawn()?;
+
+        xclip.stdin.take().ok_or("xclip stdin unavailable")?.write_all(text.as_bytes())?;
+
+        let status = xclip.wait()?;
+        if !status.success() {
+            return Err(format!("xclip exited with {}", status).into());
+        }
+
+        Ok(())
+    }
+}
+
+pub struct FileSink {
+    path: String,
+    append: bool,
+}
+
+impl OutputSink for FileSink {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn write(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(self.append)
+            .truncate(!self.append)
+            .open(&self.path)?;
+
+        writeln!(file, "{}", text)?;
+        Ok(())
+    }
+}
+
+/*
+    Keeps one section of a .bbd billboard file in sync with history, leaving the rest of the file alone:
+        # keys: <marker>
+        ...
+        # keys: end <marker>
+    The section is appended if the file doesn't have it yet.
+*/
+pub struct BbdSink {
+    path: PathBuf,
+    marker: String,
+}
+
+impl OutputSink for BbdSink {
+    fn name(&self) -> &str {
+        "bbd"
+    }
+
+    fn write(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let updated = replace_section(&contents, &self.marker, text)?;
+
+        // Via a temporary file, so the billboard never reads a half-written section
+        let temporary = util::temporary_path(&self.path);
+        std::fs::write(&temporary, updated)?;
+        std::fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+}
+
+fn section_markers(marker: &str) -> (String, String) {
+    (format!("# keys: {}", marker), format!("# keys: end {}", marker))
+}
+
+fn replace_section(contents: &str, marker: &str, text: &str) -> Result<String, String> {
+    let (begin, end) = section_markers(marker);
+
+    let lines: Vec<&str> = contents.lines().collect();
+    let begin_index = lines.iter().position(|line| line.trim() == begin);
+    let end_index = begin_index.and_then(|begin_index| {
+        lines[begin_index..].iter().position(|line| line.trim() == end).map(|offset| begin_index + offset)
+    });
+
+    let section = format!("{}\n{}\n{}", begin, text, end);
+
+    match (begin_index, end_index) {
+        (Some(begin_index), Some(end_index)) => {
+            let mut updated: Vec<&str> = lines[..begin_index].to_vec();
+            updated.push(&section);
+            updated.extend_from_slice(&lines[end_index + 1..]);
+            Ok(format!("{}\n", updated.join("\n")))
+        }
+        // Rather than guess where the section ends and overwrite something else
+        (Some(_), None) => Err(format!("'{}' has no matching '{}'", begin, end)),
+        _ if contents.trim().is_empty() => Ok(format!("{}\n", section)),
+        _ => Ok(format!("{}\n\n{}\n", contents.trim_end(), section)),
+    }
+}
+
+// Contents of the section written by BbdSink, if the file has it
+pub fn read_section(contents: &str, marker: &str) -> Option<String> {
+    let (begin, end) = section_markers(marker);
+
+    let mut lines = contents.lines().skip_while(|line| line.trim() != begin);
+    lines.next()?;
+
+    Some(lines.take_while(|line| line.trim() != end).join("\n"))
+}
+
+// Only useful headless; the TUI owns the terminal otherwise
+pub struct StdoutSink;
+
+impl OutputSink for StdoutSink {
+    fn name(&self) -> &str {
+        "stdout"
+    }
+
+    fn write(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+        let mut stdout = std::io::stdout().lock();
+        writeln!(stdout, "{}", text)?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+// Publishes the notation to the router, e.g. for an editor plugin
+pub struct OscSink {
+    client: OscClient,
+    address: String,
+}
+
+impl OutputSink for OscSink {
+    fn name(&self) -> &str {
+        "osc"
+    }
+
+    fn write(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+        self.client.send(osc_model::create_history_string_message(&self.address, text));
+        Ok(())
+    }
+}
+
+// Wayland if running under it, else X11
+pub fn clipboard() -> Box<dyn OutputSink> {
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        Box::new(WaylandClipboard)
+    } else {
+        Box::new(X11Clipboard)
+    }
+}
+
+// Text on the clipboard, from the same clipboard the "clipboard" sink writes to
+pub fn read_clipboard() -> Result<String, Box<dyn Error>> {
+    let mut text = String::new();
+
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        let (mut pipe, _) = get_contents(ClipboardType::Regular, Seat::Unspecified, PasteMimeType::Text)?;
+        pipe.read_to_string(&mut text)?;
+    } else {
+        let output = Command::new("xclip").args(["-selection", "clipboard", "-o"]).output()?;
+        if !output.status.success() {
+            return Err(format!("xclip exited with {}", output.status).into());
+        }
+        text = String::from_utf8(output.stdout)?;
+    }
+
+    Ok(text)
+}
+
+pub struct Outputs {
+    sinks: Vec<Box<dyn OutputSink>>,
+}
+
+/*
+    Start of pattern definition names when the bbd sink is in use, e.g. "keys_" for marker "keys",
+        so "$keys_a" can't clash with names the rest of the billboard file defines.
+*/
+pub fn pattern_namespace(cfg: &Config) -> String {
+    if cfg.bbd_file.is_empty() || !cfg.output_sinks.iter().any(|name| name == "bbd") {
+        return String::new();
+    }
+
+    let marker: String = cfg
+        .bbd_marker
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    format!("{}_", marker)
+}
+
+impl Outputs {
+    pub fn from_config(cfg: &Config, client: OscClient) -> Outputs {
+        let mut client = Some(client);
+        let mut sinks: Vec<Box<dyn OutputSink>> = Vec::new();
+
+        for name in &cfg.output_sinks {
+            let sink: Box<dyn OutputSink> = match name.as_str() {
+                "clipboard" | "wayland" | "x11" if !cfg.copy_to_clipboard => continue,
+                "clipboard" => clipboard(),
+                "wayland" => Box::new(WaylandClipboard),
+                "x11" => Box::new(X11Clipboard),
+                "file" => Box::new(FileSink {
+                    path: cfg.output_file.clone(),
+                    append: cfg.output_file_append,
+                }),
+                "bbd" if cfg.bbd_file.is_empty() => {
+                    logging::log("Output sink 'bbd' needs bbd_file to be set, skipping it".to_string());
+                    continue;
+                }
+                "bbd" => Box::new(BbdSink {
+                    path: PathBuf::from(&cfg.bbd_file),
+                    marker: cfg.bbd_marker.clone(),
+                }),
+                "stdout" if !logging::is_headless() => {
+                    logging::log("Output sink 'stdout' only works headless, skipping it".to_string());
+                    continue;
+                }
+                "stdout" => Box::new(StdoutSink),
+                "osc" => match client.take() {
+                    Some(client) => Box::new(OscSink {
+                        client,
+                        address: cfg.osc_address("/keyboard_history_string"),
+                    }),
+                    None => continue,
+                },
+                _ => {
+                    logging::log(format!("Unknown output sink: {}", name));
+                    continue;
+                }
+            };
+
+            sinks.push(sink);
+        }
+
+        Outputs { sinks }
+    }
+
+    pub fn publish(&mut self, text: &str) {
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.write(text) {
+                logging::log(format!("Warning: output to {} failed: {}", sink.name(), e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify() {
+        let path = std::env::temp_dir().join(format!("keys_output_{}.txt", std::process::id()));
+        let path_str = path.to_string_lossy().to_string();
+
+        let mut overwrite = FileSink { path: path_str.clone(), append: false };
+        overwrite.write("(c5):len1.0,tot1.00").unwrap();
+        overwrite.write("(e5):len1.0,tot1.00").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "(e5):len1.0,tot1.00\n");
+
+        let mut append = FileSink { path: path_str, append: true };
+        append.write("(g5):len1.0,tot1.00").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "(e5):len1.0,tot1.00\n(g5):len1.0,tot1.00\n"
+        );
+
+        std::fs::remove_file(&path).unwrap();
+
+        // Billboard sections
+        let begin = "# keys: bass";
+        let end = "# keys: end bass";
+
+        // Appended below existing content
+        let contents = "$drums = (x:1):len4,tot4.00\n";
+        let appended = replace_section(contents, "bass", "(c5):len1.0,tot1.00").unwrap();
+        assert_eq!(
+            appended,
+            format!("$drums = (x:1):len4,tot4.00\n\n{}\n(c5):len1.0,tot1.00\n{}\n", begin, end)
+        );
+
+        // Replaced in place, everything around it kept
+        let surrounded = format!("{}\n@after\n", appended);
+        let replaced = replace_section(&surrounded, "bass", "(e5 g5):len2.0,tot2.00").unwrap();
+        assert_eq!(
+            replaced,
+            format!("$drums = (x:1):len4,tot4.00\n\n{}\n(e5 g5):len2.0,tot2.00\n{}\n\n@after\n", begin, end)
+        );
+
+        // Other markers are left alone
+        assert!(replace_section(&replaced, "lead", "(a5):len1.0,tot1.00").unwrap().starts_with(&replaced));
+
+        assert!(replace_section(&format!("{}\n(c5)\n", begin), "bass", "(e5)").is_err());
+
+        assert_eq!(read_section(&replaced, "bass"), Some("(e5 g5):len2.0,tot2.00".to_string()));
+        assert_eq!(read_section(&replaced, "lead"), None);
+        assert_eq!(replace_section("", "bass", "(c5)").unwrap(), format!("{}\n(c5)\n{}\n", begin, end));
+
+        // Pattern names are namespaced by the marker only when writing to a billboard file
+        let cfg = Config {
+            output_sinks: vec!["bbd".to_string()],
+            bbd_file: "song.bbd".to_string(),
+            bbd_marker: "bass line".to_string(),
+            ..Config::default()
+        };
+        assert_eq!(pattern_namespace(&cfg), "bass_line_");
+        assert_eq!(pattern_namespace(&Config { output_sinks: vec!["clipboard".to_string()], ..cfg }), "");
+    }
+}
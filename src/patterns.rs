@@ -0,0 +1,128 @@
+use bigdecimal::{BigDecimal, Zero};
+
+/*
+    Repeated bars in stringified notation, written once as "$name = ..." definitions:
+        $a = c5:1 e5:1 g5:2
+        $b = c5:4
+        $a $a $a $b
+    Names follow the order bars first appear in ($a, $b ... $z, $aa, $ab ...), so the same
+        playing always gets the same names.
+*/
+
+// Each note of a sequence with the beats it takes up, e.g. ("c5:1,amp0.3", 1)
+pub type Token = (String, BigDecimal);
+
+pub struct Patterns {
+    // <name, body> in order of first appearance
+    pub definitions: Vec<(String, String)>,
+    // Names of all segments in order of playing
+    pub references: Vec<String>,
+}
+
+impl Patterns {
+    pub fn has_repeats(&self) -> bool {
+        self.references.len() > self.definitions.len()
+    }
+}
+
+/*
+    Split <tokens> at bar lines and name each distinct bar.
+    Notes held across a bar line keep the bars on both sides together, since a bar line
+        can only fall between notes. <prefix> keeps names of several sequences apart.
+*/
+pub fn find(tokens: &[Token], bar_length: &BigDecimal, prefix: &str) -> Patterns {
+    let mut segments: Vec<Vec<&str>> = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    let mut position = BigDecimal::zero();
+
+    for (token, beats) in tokens {
+        current.push(token);
+        position += beats;
+
+        // Zero-length notes belong to the chord starting the next bar
+        if !beats.is_zero() && (position.clone() % bar_length).is_zero() {
+            segments.push(std::mem::take(&mut current));
+        }
+    }
+
+    if !current.is_empty() {
+        segments.push(current);
+    }
+
+    let mut definitions: Vec<(String, String)> = Vec::new();
+    let mut references: Vec<String> = Vec::new();
+
+    for segment in segments {
+        let body = segment.join(" ");
+
+        let name = match definitions.iter().find(|(_, existing)| *existing == body) {
+            Some((name, _)) => name.clone(),
+            None => {
+                let name = format!("${}{}", prefix, name_for(definitions.len()));
+                definitions.push((name.clone(), body));
+                name
+            }
+        };
+
+        references.push(name);
+    }
+
+    Patterns { definitions, references }
+}
+
+// 0 => "a", 25 => "z", 26 => "aa", 27 => "ab" ...
+fn name_for(index: usize) -> String {
+    let letter = (b'a' + (index % 26) as u8) as char;
+
+    match index / 26 {
+        0 => letter.to_string(),
+        rest => format!("{}{}", name_for(rest - 1), letter),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn tokens(text: &str) -> Vec<Token> {
+        text.split_whitespace()
+            .map(|token| {
+                let beats = token.split(':').nth(1).unwrap_or("0").split(',').next().unwrap();
+                (token.to_string(), BigDecimal::from_str(beats).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn verify() {
+        let bar = BigDecimal::from(4);
+
+        let riff = tokens("c5:0 e5:2 g5:2,amp0.3 c5:0 e5:2 g5:2,amp0.3 c5:0 e5:2 g5:2,amp0.3 a5:3 x:1");
+        let patterns = find(&riff, &bar, "");
+        assert_eq!(
+            patterns.definitions,
+            vec![
+                ("$a".to_string(), "c5:0 e5:2 g5:2,amp0.3".to_string()),
+                ("$b".to_string(), "a5:3 x:1".to_string()),
+            ]
+        );
+        assert_eq!(patterns.references, vec!["$a", "$a", "$a", "$b"]);
+        assert!(patterns.has_repeats());
+
+        // Held across the bar line: both bars stay one segment
+        let held = find(&tokens("c5:2 e5:4 g5:2 c5:4"), &bar, "pads_");
+        assert_eq!(patterns_of(&held), vec!["$pads_a = c5:2 e5:4 g5:2", "$pads_b = c5:4"]);
+        assert!(!held.has_repeats());
+
+        assert_eq!(name_for(0), "a");
+        assert_eq!(name_for(25), "z");
+        assert_eq!(name_for(26), "aa");
+        assert_eq!(name_for(27), "ab");
+        assert_eq!(name_for(52), "ba");
+    }
+
+    fn patterns_of(patterns: &Patterns) -> Vec<String> {
+        patterns.definitions.iter().map(|(name, body)| format!("{} = {}", name, body)).collect()
+    }
+}
@@ -0,0 +1,233 @@
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bigdecimal::{BigDecimal, ToPrimitive};
+use rosc::OscType;
+
+use crate::logging;
+use crate::midi_translation;
+use crate::osc_client::OscClient;
+use crate::osc_model;
+use crate::output;
+use crate::shuttle::{Phrase, PhraseNote, PhraseSound};
+use crate::state::State;
+
+/*
+    Playback of parsed Shuttle Notation, e.g. to audition the clipboard or to play along with a saved phrase.
+    Notes are played with the instrument (pack for pads) and args of their section header, or the current
+        ones if there is none. Control lanes set their bus to each value in turn.
+    The bpm is read while waiting for each event, so tempo changes apply right away.
+*/
+
+// Longest sleep between checks for stop and tempo changes
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+#[derive(Debug)]
+pub enum PhraseSource {
+    Clipboard,
+    // The whole file, or the section of it written by the "bbd" output sink
+    File { path: String, marker: Option<String> },
+    Text(String),
+}
+
+impl PhraseSource {
+    pub fn read(&self) -> Result<String, Box<dyn Error>> {
+        match self {
+            PhraseSource::Clipboard => output::read_clipboard(),
+            PhraseSource::File { path, marker } => {
+                let contents = std::fs::read_to_string(path)?;
+                match marker {
+                    Some(marker) => output::read_section(&contents, marker)
+                        .ok_or(format!("No section '{}' in {}", marker, path).into()),
+                    None => Ok(contents),
+                }
+            }
+            PhraseSource::Text(text) => Ok(text.clone()),
+        }
+    }
+}
+
+enum Action {
+    Play(PhraseNote, Option<PhraseSound>),
+    Release(u8),
+    // <bus, value>
+    Control(i32, f32),
+}
+
+pub struct Player {
+    // Cleared by the playback thread when done, or by stop()
+    playing: Option<Arc<AtomicBool>>,
+}
+
+impl Player {
+    pub fn new() -> Player {
+        Player { playing: None }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing.as_ref().is_some_and(|playing| playing.load(Ordering::Relaxed))
+    }
+
+    // Play phrases side by side, once or until stopped. Anything already playing is stopped first.
+    pub fn play(&mut self, phrases: Vec<Phrase>, looping: bool, client: OscClient, state: Arc<Mutex<State>>) {
+        self.stop();
+
+        let length = phrases
+            .iter()
+            .map(|phrase| phrase.length.clone())
+            .max()
+            .and_then(|length| length.to_f64())
+            .unwrap_or(0.0);
+
+        if length <= 0.0 {
+            logging::log("Nothing to play in phrase".to_string());
+            return;
+        }
+
+        let schedule = schedule(phrases, length);
+        let playing = Arc::new(AtomicBool::new(true));
+        self.playing = Some(playing.clone());
+
+        thread::spawn(move || {
+            let mut clock = Clock { position: 0.0, last: Instant::now() };
+            let mut held: Vec<u8> = Vec::new();
+
+            'passes: loop {
+                for (at, action) in &schedule {
+                    if !clock.wait_until(*at, &playing, &state) {
+                        break 'passes;
+                    }
+                    perform(action, &mut held, &client, &state);
+                }
+
+                if !looping || !clock.wait_until(length, &playing, &state) {
+                    break;
+                }
+
+                // Keep any overshoot, so loops don't drift
+                clock.position -= length;
+            }
+
+            for tone in held {
+                client.send(osc_model::create_phrase_note_off(tone as i32));
+            }
+
+            playing.store(false, Ordering::Relaxed);
+        });
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(playing) = self.playing.take() {
+            playing.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+// Position in beats, moved along by elapsed time at the current bpm
+struct Clock {
+    position: f64,
+    last: Instant,
+}
+
+impl Clock {
+    // Sleep until <beat>; false if playback was stopped meanwhile
+    fn wait_until(&mut self, beat: f64, playing: &AtomicBool, state: &Mutex<State>) -> bool {
+        loop {
+            if !playing.load(Ordering::Relaxed) {
+                return false;
+            }
+
+            let bpm = state.lock().unwrap().bpm.max(1) as f64;
+            let now = Instant::now();
+            self.position += now.duration_since(self.last).as_secs_f64() * bpm / 60.0;
+            self.last = now;
+
+            if self.position >= beat {
+                return true;
+            }
+
+            let remaining = Duration::from_secs_f64((beat - self.position) * 60.0 / bpm);
+            thread::sleep(remaining.min(POLL_INTERVAL));
+        }
+    }
+}
+
+/*
+    Onsets, releases and control values of all phrases in time order, in beats.
+    Releases and control values go before onsets on the same beat. Releases are kept within <length>
+        so every pass ends silent.
+*/
+fn schedule(phrases: Vec<Phrase>, length: f64) -> Vec<(f64, Action)> {
+    let mut schedule: Vec<(f64, Action)> = Vec::new();
+
+    for (note, phrase) in phrases.iter().flat_map(|phrase| phrase.notes.iter().map(move |note| (note, phrase))) {
+        let at = note.at.to_f64().unwrap_or(0.0);
+
+        if let Some(bus) = phrase.bus {
+            match note.id.parse::<f32>() {
+                Ok(value) => schedule.push((at, Action::Control(bus, value))),
+                Err(_) => logging::log(format!("Skipping unknown value on bus {}: {}", bus, note.id)),
+            }
+            continue;
+        }
+
+        if !note.is_sample {
+            let Some(tone) = midi_translation::oletter_to_tone(&note.id) else {
+                logging::log(format!("Skipping unknown note in phrase: {}", note.id));
+                continue;
+            };
+
+            let release = at + note.sustain.as_ref().and_then(BigDecimal::to_f64).unwrap_or(0.0);
+            schedule.push((release.min(length), Action::Release(tone)));
+        }
+
+        schedule.push((at, Action::Play(note.clone(), phrase.sound.clone())));
+    }
+
+    schedule.sort_by(|(a, a_action), (b, b_action)| {
+        a.total_cmp(b).then(matches!(a_action, Action::Play(..)).cmp(&matches!(b_action, Action::Play(..))))
+    });
+
+    schedule
+}
+
+fn perform(action: &Action, held: &mut Vec<u8>, client: &OscClient, state: &Mutex<State>) {
+    match action {
+        Action::Play(note, sound) => {
+            let state = state.lock().unwrap();
+
+            // The header sound replaces the current one, args and all
+            let (instrument, mut args) = match (sound, note.is_sample) {
+                (Some(sound), _) => (sound.instrument.as_str(), osc_args(&sound.args)),
+                (None, true) => (state.pads_configuration.pack_name.as_str(), state.pads_configuration.args.clone()),
+                (None, false) => (state.instrument_name.as_str(), state.message_args.clone()),
+            };
+            args.extend(osc_args(&note.args));
+
+            if note.is_sample {
+                let index = note.id.parse::<i32>().unwrap_or(0);
+                client.send(osc_model::create_play_sample(index, instrument, args));
+            } else if let Some(tone) = midi_translation::oletter_to_tone(&note.id) {
+                client.send(osc_model::create_phrase_note_on(tone as i32, instrument, args));
+                held.push(tone);
+            }
+        }
+        Action::Control(bus, value) => client.send(osc_model::create_control_bus_mod(*bus, *value)),
+        Action::Release(tone) => {
+            client.send(osc_model::create_phrase_note_off(*tone as i32));
+            if let Some(index) = held.iter().position(|held_tone| held_tone == tone) {
+                held.remove(index);
+            }
+        }
+    }
+}
+
+// ("amp", 0.3) -> "amp", 0.3
+fn osc_args(args: &[(String, BigDecimal)]) -> Vec<OscType> {
+    args.iter()
+        .flat_map(|(name, value)| [OscType::String(name.clone()), OscType::Float(value.to_f32().unwrap_or(0.0))])
+        .collect()
+}
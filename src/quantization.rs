@@ -0,0 +1,258 @@
+use std::str::FromStr;
+
+use bigdecimal::{BigDecimal, FromPrimitive, RoundingMode, Zero};
+use rosc::OscType;
+
+use crate::config::Config;
+use crate::util;
+
+/*
+    Quantization grid for recorded history.
+    The grid step is divided by a tuplet (3 = triplets), every second grid point can be swung late
+        and strength pulls notes only part of the way towards the grid.
+*/
+
+// Grid presets for the TUI selector: label, step in beats, tuplet
+pub const GRID_PRESETS: [(&str, &str, u32); 9] = [
+    ("1/4", "1", 1),
+    ("1/8", "0.5", 1),
+    ("1/16", "0.25", 1),
+    ("1/32", "0.125", 1),
+    ("1/4T", "1", 3),
+    ("1/8T", "0.5", 3),
+    ("1/16T", "0.25", 3),
+    ("1/32T", "0.125", 3),
+    ("1/16Q", "0.25", 5),
+];
+
+// Finest resolution written to notation; tuplets and partial strength would otherwise never terminate
+const RESOLUTION: &str = "0.001";
+
+pub const SWING_STRAIGHT: u8 = 50;
+pub const SWING_MAX: u8 = 75;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Quantization {
+    // Grid step in beats, before tuplet division
+    pub step: BigDecimal,
+    // Notes per power-of-two group, e.g. 3 fits three notes in the space of two steps
+    pub tuplet: u32,
+    // Position of every second grid point within a pair, in percent: 50 is straight, 66 is triplet swing
+    pub swing: u8,
+    // How far notes are pulled towards the grid, in percent
+    pub strength: u8,
+}
+
+impl Quantization {
+    pub fn from_config(cfg: &Config) -> Quantization {
+        Quantization {
+            // Checked to be positive by config::load
+            step: BigDecimal::from_str(&cfg.quantization).unwrap(),
+            tuplet: cfg.quantize_tuplet.max(1),
+            swing: cfg.quantize_swing.clamp(SWING_STRAIGHT, SWING_MAX),
+            strength: cfg.quantize_strength.min(100),
+        }
+    }
+
+    // Used when quantization is toggled off: keeps timing as played, at the output resolution
+    pub fn off() -> Quantization {
+        Quantization {
+            step: BigDecimal::from_str(RESOLUTION).unwrap(),
+            tuplet: 1,
+            swing: SWING_STRAIGHT,
+            strength: 100,
+        }
+    }
+
+    pub fn label(&self) -> String {
+        let mut label = self.step.to_string();
+        if self.tuplet != 1 {
+            label += &format!(" t{}", self.tuplet);
+        }
+        if self.swing != SWING_STRAIGHT {
+            label += &format!(" sw{}", self.swing);
+        }
+        if self.strength != 100 {
+            label += &format!(" st{}", self.strength);
+        }
+        label
+    }
+
+    pub fn apply_preset(&mut self, label: &str) -> bool {
+        match GRID_PRESETS.iter().find(|(name, _, _)| *name == label) {
+            Some((_, step, tuplet)) => {
+                self.step = BigDecimal::from_str(step).unwrap();
+                self.tuplet = *tuplet;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn adjust_swing(&mut self, amount: i32) {
+        self.swing = (self.swing as i32 + amount).clamp(SWING_STRAIGHT as i32, SWING_MAX as i32) as u8;
+    }
+
+    pub fn adjust_strength(&mut self, amount: i32) {
+        self.strength = (self.strength as i32 + amount).clamp(0, 100) as u8;
+    }
+
+    /*
+        Apply OSC arguments: an optional leading step string, followed by key-value pairs,
+            e.g. ["0.25", "tuplet", 3, "swing", 60, "strength", 80]
+    */
+    pub fn apply_args(&mut self, args: &[OscType]) -> Result<(), String> {
+        let mut rest = args;
+
+        if let Some(OscType::String(step)) = args.first() {
+            if let Ok(step) = BigDecimal::from_str(step) {
+                if step <= BigDecimal::zero() {
+                    return Err(format!("Quantization step must be positive: {}", step));
+                }
+                self.step = step;
+                rest = &args[1..];
+            }
+        }
+
+        for pair in rest.chunks(2) {
+            let key = match &pair[0] {
+                OscType::String(key) => key.as_str(),
+                other => return Err(format!("Expected a quantization setting name, got {:?}", other)),
+            };
+
+            let value = pair
+                .get(1)
+                .and_then(number_arg)
+                .ok_or(format!("Missing or invalid value for quantization setting '{}'", key))?;
+
+            match key {
+                "tuplet" => self.tuplet = (value as u32).max(1),
+                "swing" => self.swing = (value as i64).clamp(SWING_STRAIGHT as i64, SWING_MAX as i64) as u8,
+                "strength" => self.strength = (value as i64).clamp(0, 100) as u8,
+                _ => return Err(format!("Unknown quantization setting: {}", key)),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Step after tuplet division: a tuplet of n fits n notes where the largest power of two below n would be
+    pub fn grid_step(&self) -> BigDecimal {
+        let tuplet = self.tuplet.max(1);
+        let base = 1u32 << (31 - tuplet.leading_zeros());
+        self.step.clone() * BigDecimal::from_u32(base).unwrap() / BigDecimal::from_u32(tuplet).unwrap()
+    }
+
+    /*
+        Quantize a gap between two onsets (relative mode), left unrounded: callers round the running
+            position instead, so three triplet gaps still add up to a whole beat.
+        Swing needs a grid origin, so swung relative sequences use snap_position from the take start.
+    */
+    pub fn snap_gap(&self, beats: BigDecimal) -> BigDecimal {
+        let snapped = util::round_to_nearest(beats.clone(), self.grid_step());
+        self.pull(beats, snapped)
+    }
+
+    // Quantize an onset counted from the grid origin (grid mode)
+    pub fn snap_position(&self, beats: BigDecimal) -> BigDecimal {
+        let pair = self.grid_step() * BigDecimal::from_u8(2).unwrap();
+        let pair_start = (beats.clone() / pair.clone()).with_scale_round(0, RoundingMode::Floor) * pair.clone();
+        let off_beat = pair_start.clone()
+            + pair.clone() * BigDecimal::from_u8(self.swing).unwrap() / BigDecimal::from_u8(100).unwrap();
+
+        let snapped = [pair_start.clone(), off_beat, pair_start + pair]
+            .into_iter()
+            .min_by_key(|point| (point.clone() - beats.clone()).abs())
+            .unwrap();
+
+        self.round(self.pull(beats, snapped))
+    }
+
+    pub fn is_swung(&self) -> bool {
+        self.swing != SWING_STRAIGHT
+    }
+
+    // Round a position to the notation resolution
+    pub fn round(&self, beats: BigDecimal) -> BigDecimal {
+        // Leave plain grids untouched so their notation stays as before
+        if beats.as_bigint_and_exponent().1 > 3 {
+            util::round_to_nearest(beats, BigDecimal::from_str(RESOLUTION).unwrap())
+        } else {
+            beats
+        }
+    }
+
+    fn pull(&self, raw: BigDecimal, snapped: BigDecimal) -> BigDecimal {
+        if self.strength >= 100 {
+            snapped
+        } else {
+            raw.clone() + (snapped - raw) * BigDecimal::from_u8(self.strength).unwrap() / BigDecimal::from_u8(100).unwrap()
+        }
+    }
+}
+
+fn number_arg(arg: &OscType) -> Option<f64> {
+    match arg {
+        OscType::Int(value) => Some(*value as f64),
+        OscType::Float(value) => Some(*value as f64),
+        OscType::Double(value) => Some(*value),
+        OscType::String(value) => f64::from_str(value).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn beats(value: &str) -> BigDecimal {
+        BigDecimal::from_str(value).unwrap()
+    }
+
+    #[test]
+    fn verify() {
+        let mut quantization = Quantization { step: beats("0.5"), tuplet: 1, swing: 50, strength: 100 };
+        assert_eq!(quantization.snap_gap(beats("0.6")), beats("0.5"));
+
+        // Triplet eighths: three notes per beat, rounded as positions so the gaps add up to the beat
+        quantization.tuplet = 3;
+        assert_eq!(quantization.round(quantization.snap_gap(beats("0.3"))), beats("0.333"));
+        let mut position = BigDecimal::zero();
+        let rounded: Vec<BigDecimal> = ["0.3", "0.35", "0.32"]
+            .iter()
+            .map(|gap| {
+                position = position.clone() + quantization.snap_gap(beats(gap));
+                quantization.round(position.clone())
+            })
+            .collect();
+        assert_eq!(rounded, vec![beats("0.333"), beats("0.667"), beats("1")]);
+
+        // Swung off-beat at 2/3 of the pair
+        quantization.tuplet = 1;
+        quantization.swing = 66;
+        assert_eq!(quantization.snap_position(beats("0.6")), beats("0.66"));
+        assert_eq!(quantization.snap_position(beats("0.2")), beats("0"));
+
+        // Half strength moves halfway to the grid
+        quantization.swing = 50;
+        quantization.strength = 50;
+        assert_eq!(quantization.snap_position(beats("0.6")), beats("0.55"));
+
+        quantization
+            .apply_args(&[
+                OscType::String("0.25".to_string()),
+                OscType::String("tuplet".to_string()),
+                OscType::Int(3),
+                OscType::String("swing".to_string()),
+                OscType::Float(90.0),
+            ])
+            .unwrap();
+        assert_eq!(quantization.step, beats("0.25"));
+        assert_eq!(quantization.tuplet, 3);
+        assert_eq!(quantization.swing, SWING_MAX);
+        assert!(quantization.apply_args(&[OscType::String("groove".to_string()), OscType::Int(1)]).is_err());
+
+        assert!(quantization.apply_preset("1/8T"));
+        assert_eq!(quantization.label(), "0.5 t3 sw75 st50");
+    }
+}
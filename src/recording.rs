@@ -0,0 +1,227 @@
+/*
+    Record arming: decides whether played notes end up in history.
+    Fed with note and loop start events; punch-out stops recording after a set amount of loops.
+    With a count-in, recording (or arming) waits for that many bars of metronome clicks.
+*/
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RecordMode {
+    // Record from the moment recording is toggled on
+    Always,
+    // Arm on toggle, start recording with the first played note
+    FirstNote,
+    // Arm on toggle, start recording at the next loop start
+    NextLoop,
+}
+
+impl RecordMode {
+    pub fn from_name(name: &str) -> Option<RecordMode> {
+        match name.to_lowercase().as_str() {
+            "always" => Some(RecordMode::Always),
+            "first_note" => Some(RecordMode::FirstNote),
+            "next_loop" => Some(RecordMode::NextLoop),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RecordMode::Always => "always",
+            RecordMode::FirstNote => "first_note",
+            RecordMode::NextLoop => "next_loop",
+        }
+    }
+
+    pub fn next(&self) -> RecordMode {
+        match self {
+            RecordMode::Always => RecordMode::FirstNote,
+            RecordMode::FirstNote => RecordMode::NextLoop,
+            RecordMode::NextLoop => RecordMode::Always,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RecordStatus {
+    Off,
+    CountIn,
+    Armed,
+    Recording,
+}
+
+impl RecordStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RecordStatus::Off => "off",
+            RecordStatus::CountIn => "count_in",
+            RecordStatus::Armed => "armed",
+            RecordStatus::Recording => "recording",
+        }
+    }
+}
+
+pub struct Recorder {
+    pub mode: RecordMode,
+    pub status: RecordStatus,
+    // Stop recording at the Nth loop start after recording began
+    pub punch_out_loops: Option<u32>,
+    loops_recorded: u32,
+    // Bars to count in before recording starts (0 = none)
+    pub count_in_bars: u32,
+    count_in_remaining: u32,
+}
+
+impl Recorder {
+    pub fn new(mode: RecordMode, enabled: bool, punch_out_loops: Option<u32>, count_in_bars: u32) -> Recorder {
+        let mut recorder = Recorder {
+            mode,
+            status: RecordStatus::Off,
+            punch_out_loops,
+            loops_recorded: 0,
+            count_in_bars,
+            count_in_remaining: 0,
+        };
+
+        if enabled {
+            recorder.start();
+        }
+
+        recorder
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.status == RecordStatus::Recording
+    }
+
+    pub fn is_counting_in(&self) -> bool {
+        self.status == RecordStatus::CountIn
+    }
+
+    // Armed or recording, i.e. interested in loop starts
+    pub fn is_active(&self) -> bool {
+        self.status != RecordStatus::Off
+    }
+
+    pub fn toggle(&mut self) {
+        if self.is_active() {
+            self.status = RecordStatus::Off;
+        } else {
+            self.start();
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if enabled != self.is_active() {
+            self.toggle();
+        }
+    }
+
+    // Changing mode re-arms according to the new mode, unless recording is off
+    pub fn set_mode(&mut self, mode: RecordMode) {
+        self.mode = mode;
+        if self.is_active() {
+            self.start();
+        }
+    }
+
+    fn start(&mut self) {
+        self.loops_recorded = 0;
+
+        if self.count_in_bars > 0 {
+            self.count_in_remaining = self.count_in_bars;
+            self.status = RecordStatus::CountIn;
+        } else {
+            self.begin();
+        }
+    }
+
+    fn begin(&mut self) {
+        self.status = match self.mode {
+            RecordMode::Always => RecordStatus::Recording,
+            RecordMode::FirstNote | RecordMode::NextLoop => RecordStatus::Armed,
+        };
+    }
+
+    // Returns true if the played note should be recorded
+    pub fn on_note(&mut self) -> bool {
+        if self.status == RecordStatus::Armed && self.mode == RecordMode::FirstNote {
+            self.status = RecordStatus::Recording;
+        }
+
+        self.is_recording()
+    }
+
+    /*
+        Called by the metronome on the first beat of every bar.
+        A count-in starts with the next bar, so a count-in of 2 bars ends on the third downbeat.
+    */
+    pub fn on_downbeat(&mut self) {
+        if self.status == RecordStatus::CountIn {
+            if self.count_in_remaining == 0 {
+                self.begin();
+            } else {
+                self.count_in_remaining -= 1;
+            }
+        }
+    }
+
+    pub fn on_loop_start(&mut self) {
+        match self.status {
+            RecordStatus::Armed if self.mode == RecordMode::NextLoop => {
+                self.status = RecordStatus::Recording;
+                self.loops_recorded = 0;
+            }
+            RecordStatus::Recording => {
+                self.loops_recorded += 1;
+                if let Some(limit) = self.punch_out_loops {
+                    if self.loops_recorded >= limit {
+                        self.status = RecordStatus::Off;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify() {
+        let mut always = Recorder::new(RecordMode::Always, true, None, 0);
+        assert!(always.on_note());
+        always.toggle();
+        assert!(!always.on_note());
+
+        let mut first_note = Recorder::new(RecordMode::FirstNote, true, None, 0);
+        assert_eq!(first_note.status, RecordStatus::Armed);
+        first_note.on_loop_start();
+        assert_eq!(first_note.status, RecordStatus::Armed);
+        assert!(first_note.on_note());
+
+        let mut next_loop = Recorder::new(RecordMode::NextLoop, true, Some(2), 0);
+        assert!(!next_loop.on_note());
+        next_loop.on_loop_start();
+        assert!(next_loop.on_note());
+        next_loop.on_loop_start();
+        assert!(next_loop.is_recording());
+        next_loop.on_loop_start();
+        assert_eq!(next_loop.status, RecordStatus::Off);
+        assert!(!next_loop.on_note());
+
+        // Toggled mid-bar: the rest of that bar and two full bars pass before recording
+        let mut counted = Recorder::new(RecordMode::Always, false, None, 2);
+        counted.toggle();
+        assert!(counted.is_counting_in());
+        assert!(!counted.on_note());
+        counted.on_downbeat();
+        counted.on_downbeat();
+        assert!(counted.is_counting_in());
+        counted.on_downbeat();
+        assert!(counted.on_note());
+        counted.on_downbeat();
+        assert!(counted.is_recording());
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use bigdecimal::BigDecimal;
+use rosc::OscType;
+use serde::{Deserialize, Serialize};
+
+use crate::event_history::EventHistory;
+use crate::event_model::{BeatBreak, Control, Event, NoteOff, NoteOn, Silence};
+use crate::quantization::Quantization;
+use crate::state::State;
+use crate::util;
+
+/*
+    Session file: recorded history plus the state tweaked while playing, saved as TOML.
+    Event times are stored in microseconds from the start of history, since Instants only mean
+        something within the process that created them.
+*/
+
+const SESSION_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct Session {
+    version: u32,
+    bpm: i64,
+    quantization: SessionQuantization,
+    instrument: String,
+    args: Vec<SessionArg>,
+    pack: String,
+    pad_args: Vec<SessionArg>,
+    // <pad_id, sample_index> for pads not playing their own index
+    pad_map: Vec<(u8, i32)>,
+    events: Vec<SessionEvent>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct SessionQuantization {
+    step: String,
+    tuplet: u32,
+    swing: u8,
+    strength: u8,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(untagged)]
+enum SessionArg {
+    Int(i32),
+    Float(f32),
+    Text(String),
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum SessionEvent {
+    NoteOn {
+        at_us: u64,
+        id: String,
+        is_sample: bool,
+        velocity: u8,
+        #[serde(default)]
+        instrument: String,
+        #[serde(default)]
+        args: Vec<SessionArg>,
+    },
+    NoteOff {
+        at_us: u64,
+        id: String,
+    },
+    Control {
+        at_us: u64,
+        bus: i32,
+        value: f32,
+    },
+    Silence {
+        at_us: u64,
+    },
+    BeatBreak {
+        at_us: u64,
+    },
+}
+
+impl Session {
+    pub fn capture(state: &State, history: &EventHistory) -> Session {
+        let origin = history.start_time().unwrap_or(Instant::now());
+        let at_us = |time: &Instant| time.saturating_duration_since(origin).as_micros() as u64;
+
+        let events = history
+            .events()
+            .iter()
+            .map(|event| match event {
+                Event::NoteOn(note_on) => SessionEvent::NoteOn {
+                    at_us: at_us(&note_on.time),
+                    id: note_on.id.clone(),
+                    is_sample: note_on.is_sample,
+                    velocity: note_on.velocity,
+                    instrument: note_on.instrument.clone(),
+                    args: to_session_args(&note_on.args),
+                },
+                Event::NoteOff(note_off) => SessionEvent::NoteOff {
+                    at_us: at_us(&note_off.time),
+                    id: note_off.id.clone(),
+                },
+                Event::Control(control) => SessionEvent::Control {
+                    at_us: at_us(&control.time),
+                    bus: control.bus,
+                    value: control.value,
+                },
+                Event::Silence(silence) => SessionEvent::Silence { at_us: at_us(&silence.time) },
+                Event::BeatBreak(beatbreak) => SessionEvent::BeatBreak { at_us: at_us(&beatbreak.time) },
+            })
+            .collect();
+
+        let mut pad_map: Vec<(u8, i32)> = state
+            .pads_configuration
+            .pads
+            .iter()
+            .filter(|(pad_id, sample_index)| **pad_id as i32 != **sample_index)
+            .map(|(pad_id, sample_index)| (*pad_id, *sample_index))
+            .collect();
+        pad_map.sort();
+
+        Session {
+            version: SESSION_VERSION,
+            bpm: state.bpm,
+            quantization: SessionQuantization {
+                step: state.quantization.step.to_string(),
+                tuplet: state.quantization.tuplet,
+                swing: state.quantization.swing,
+                strength: state.quantization.strength,
+            },
+            instrument: state.instrument_name.clone(),
+            args: to_session_args(&state.message_args),
+            pack: state.pads_configuration.pack_name.clone(),
+            pad_args: to_session_args(&state.pads_configuration.args),
+            pad_map,
+            events,
+        }
+    }
+
+    /*
+        Replace state and history with the session contents.
+        Restored history is placed so that it ends now, and anything played next follows on from it.
+    */
+    pub fn apply(self, state: &mut State, history: &mut EventHistory) -> Result<(), Box<dyn Error>> {
+        if self.version != SESSION_VERSION {
+            return Err(format!("Unsupported session version: {}", self.version).into());
+        }
+
+        state.bpm = self.bpm;
+        state.quantization = Quantization {
+            step: BigDecimal::from_str(&self.quantization.step)?,
+            tuplet: self.quantization.tuplet,
+            swing: self.quantization.swing,
+            strength: self.quantization.strength,
+        };
+        state.instrument_name = self.instrument;
+        state.message_args = from_session_args(self.args);
+        state.pads_configuration.pack_name = self.pack;
+        state.pads_configuration.args = from_session_args(self.pad_args);
+
+        let mut pads: HashMap<u8, i32> = (0u8..128u8).map(|value| (value, value as i32)).collect();
+        pads.extend(self.pad_map);
+        state.pads_configuration.pads = pads;
+
+        let span = self.events.iter().map(SessionEvent::at_us).max().unwrap_or(0);
+        let now = Instant::now();
+        let origin = now.checked_sub(Duration::from_micros(span)).unwrap_or(now);
+        let time = |at_us: u64| origin + Duration::from_micros(at_us);
+
+        let events = self
+            .events
+            .into_iter()
+            .map(|event| match event {
+                SessionEvent::NoteOn { at_us, id, is_sample, velocity, instrument, args } => Event::NoteOn(NoteOn {
+                    id,
+                    time: time(at_us),
+                    is_sample,
+                    velocity,
+                    instrument,
+                    args: from_session_args(args),
+                }),
+                SessionEvent::NoteOff { at_us, id } => Event::NoteOff(NoteOff { id, time: time(at_us) }),
+                SessionEvent::Control { at_us, bus, value } => Event::Control(Control { bus, value, time: time(at_us) }),
+                SessionEvent::Silence { at_us } => Event::Silence(Silence { time: time(at_us) }),
+                SessionEvent::BeatBreak { at_us } => Event::BeatBreak(BeatBreak { time: time(at_us) }),
+            })
+            .collect();
+
+        history.restore(events);
+
+        Ok(())
+    }
+}
+
+impl SessionEvent {
+    fn at_us(&self) -> u64 {
+        match self {
+            SessionEvent::NoteOn { at_us, .. }
+            | SessionEvent::NoteOff { at_us, .. }
+            | SessionEvent::Control { at_us, .. }
+            | SessionEvent::Silence { at_us }
+            | SessionEvent::BeatBreak { at_us } => *at_us,
+        }
+    }
+}
+
+pub fn load(path: &Path) -> Result<Session, Box<dyn Error>> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(toml::from_str(&contents)?)
+}
+
+// Written to a temporary file first, so a crash mid-write never leaves a broken session behind
+pub fn save(path: &Path, session: &Session) -> Result<(), Box<dyn Error>> {
+    let contents = toml::to_string(session)?;
+    let temporary = util::temporary_path(path);
+    std::fs::write(&temporary, contents)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+fn to_session_args(args: &[OscType]) -> Vec<SessionArg> {
+    args.iter()
+        .filter_map(|arg| match arg {
+            OscType::Int(value) => Some(SessionArg::Int(*value)),
+            OscType::Float(value) => Some(SessionArg::Float(*value)),
+            OscType::String(value) => Some(SessionArg::Text(value.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+fn from_session_args(args: Vec<SessionArg>) -> Vec<OscType> {
+    args.into_iter()
+        .map(|arg| match arg {
+            SessionArg::Int(value) => OscType::Int(value),
+            SessionArg::Float(value) => OscType::Float(value),
+            SessionArg::Text(value) => OscType::String(value),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify() {
+        crate::config::init(None, None).unwrap();
+
+        let start = Instant::now();
+        let mut state = State::new();
+        state.bpm = 96;
+        state.instrument_name = "bass".to_string();
+        state.message_args = vec![OscType::String("amp".to_string()), OscType::Float(0.5)];
+        state.pads_configuration.pads.insert(3, 40);
+
+        let mut history = EventHistory::new();
+        history.add(Event::NoteOn(NoteOn {
+            id: "c5".to_string(),
+            time: start,
+            is_sample: false,
+            velocity: 90,
+            instrument: "bass".to_string(),
+            args: state.message_args.clone(),
+        }));
+        history.add(Event::Control(Control { bus: 2, value: 0.75, time: start + Duration::from_millis(100) }));
+        history.add(Event::NoteOff(NoteOff { id: "c5".to_string(), time: start + Duration::from_millis(250) }));
+        history.register_beatbreak(start + Duration::from_millis(1000));
+
+        let session = Session::capture(&state, &history);
+        let serialized = toml::to_string(&session).unwrap();
+        let parsed: Session = toml::from_str(&serialized).unwrap();
+        assert_eq!(parsed, session);
+
+        let mut restored_state = State::new();
+        let mut restored_history = EventHistory::new();
+        parsed.apply(&mut restored_state, &mut restored_history).unwrap();
+
+        assert_eq!(restored_state.bpm, 96);
+        assert_eq!(restored_state.instrument_name, "bass");
+        assert_eq!(restored_state.message_args, state.message_args);
+        assert_eq!(restored_state.pads_configuration.pads.get(&3), Some(&40));
+        assert_eq!(restored_state.pads_configuration.pads.get(&4), Some(&4));
+
+        // Same events at the same distances, just moved in time
+        assert_eq!(Session::capture(&restored_state, &restored_history), session);
+        assert!(restored_history.modified);
+    }
+}
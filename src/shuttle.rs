@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use bigdecimal::{BigDecimal, Zero};
+
+/*
+    Reading back the Shuttle Notation subset written by stringify_history:
+        (c5:1,amp0.3 e5:0,sus0.5 . x:1 12:3):len4.0,tot1.00
+    plus "# ..." comment lines and the multiline form of "$name = ..." definitions followed
+        by a line of references. Each sequence line is a phrase; phrases play side by side, like lanes.
+    A "# synth: superpiano amp0.5" header gives the sequence after it its own instrument (pack for pads) and args.
+    A "# bus 3" header makes the sequence after it a control lane: its "notes" are values of control bus 3.
+*/
+
+const SILENCE_REP: &str = "x";
+const BEAT_BREAK_REP: &str = ".";
+const SUSTAIN_ARG: &str = "sus";
+// Definitions referring to definitions; deeper than this is assumed to be a cycle
+const MAX_NESTING: usize = 16;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct PhraseNote {
+    // Note letter ("c5"), sample index ("12") or control value ("0.4")
+    pub id: String,
+    pub is_sample: bool,
+    // Onset in beats from the start of the phrase
+    pub at: BigDecimal,
+    // Beats until release; only synth notes are released
+    pub sustain: Option<BigDecimal>,
+    // Args other than sustain, e.g. ("amp", 0.3)
+    pub args: Vec<(String, BigDecimal)>,
+}
+
+// Instrument (pack for pads) and args, as written in a section header
+#[derive(Clone, PartialEq, Debug)]
+pub struct PhraseSound {
+    pub instrument: String,
+    pub args: Vec<(String, BigDecimal)>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Phrase {
+    pub notes: Vec<PhraseNote>,
+    // Loop length in beats: "len" if given, else the sum of all note times
+    pub length: BigDecimal,
+    // From the header above the sequence; None plays it with the current sound
+    pub sound: Option<PhraseSound>,
+    // Control bus for a control lane, from its "# bus <n>" header
+    pub bus: Option<i32>,
+}
+
+enum Header {
+    Sound(PhraseSound),
+    Bus(i32),
+}
+
+// <legato_arg> is the name used for held time as a fraction of the reserved time (config: sustain_legato_arg)
+pub fn parse(text: &str, legato_arg: &str) -> Result<Vec<Phrase>, String> {
+    let mut definitions: HashMap<String, String> = HashMap::new();
+    // <sequence line, header above it>
+    let mut sequences: Vec<(&str, Option<Header>)> = Vec::new();
+    let mut header: Option<Header> = None;
+
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('#') {
+            if let Some(parsed) = parse_header(line)? {
+                header = Some(parsed);
+            }
+            continue;
+        }
+
+        if line.is_empty() {
+            continue;
+        }
+
+        match line.split_once('=') {
+            Some((name, body)) if line.starts_with('$') => {
+                definitions.insert(name.trim().to_string(), body.trim().to_string());
+            }
+            _ => sequences.push((line, header.take())),
+        }
+    }
+
+    if sequences.is_empty() {
+        return Err("No sequence found".to_string());
+    }
+
+    sequences
+        .into_iter()
+        .map(|(line, header)| {
+            let is_control = matches!(header, Some(Header::Bus(_)));
+            let phrase = parse_sequence(line, &definitions, legato_arg, is_control)?;
+
+            Ok(match header {
+                Some(Header::Bus(bus)) => Phrase { bus: Some(bus), ..phrase },
+                Some(Header::Sound(sound)) => Phrase { sound: Some(sound), ..phrase },
+                None => phrase,
+            })
+        })
+        .collect()
+}
+
+/*
+    "# bus <n>" above a control lane, or a sound from a "# <lane>: <instrument> <args>" header,
+        e.g. "# pads: drums amp0.5,ofs0". Other comments, like "# synth" without a sound, are not headers.
+*/
+fn parse_header(line: &str) -> Result<Option<Header>, String> {
+    let comment = line.trim_start_matches('#').trim();
+
+    if let Some(bus) = comment.strip_prefix("bus ") {
+        return Ok(bus.trim().parse::<i32>().ok().map(Header::Bus));
+    }
+
+    let Some((name, sound)) = comment.split_once(':') else {
+        return Ok(None);
+    };
+
+    // Chord annotations and the markers around a billboard file section
+    if matches!(name.trim(), "chords" | "keys") {
+        return Ok(None);
+    }
+
+    let mut parts = sound.split_whitespace();
+    let Some(instrument) = parts.next() else {
+        return Ok(None);
+    };
+
+    let args = parts
+        .flat_map(|args| args.split(','))
+        .map(|arg| {
+            let (name, value) = split_arg(arg)?;
+            Ok((name.to_string(), parse_number(value)?))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    Ok(Some(Header::Sound(PhraseSound { instrument: instrument.to_string(), args })))
+}
+
+// In a control lane (<is_control>) notes are values: none are samples, and none are released
+fn parse_sequence(
+    line: &str,
+    definitions: &HashMap<String, String>,
+    legato_arg: &str,
+    is_control: bool,
+) -> Result<Phrase, String> {
+    // "(<notes>):len4.0,tot1.00" or bare notes
+    let (body, suffix) = match (line.strip_prefix('('), line.rfind(')')) {
+        (Some(_), Some(close)) => (&line[1..close], &line[close + 1..]),
+        _ => (line, ""),
+    };
+
+    let mut length: Option<BigDecimal> = None;
+    for part in suffix.trim_start_matches(':').split(',').filter(|part| !part.is_empty()) {
+        if let Some(value) = part.strip_prefix("len") {
+            length = Some(parse_number(value)?);
+        } else if part.strip_prefix("tot").is_none() {
+            return Err(format!("Unsupported sequence arg: {}", part));
+        }
+    }
+
+    let mut tokens: Vec<String> = Vec::new();
+    expand(body, definitions, 0, &mut tokens)?;
+
+    let mut notes: Vec<PhraseNote> = Vec::new();
+    // Onsets of everything taking up time, for releasing notes without sustain
+    let mut onsets: Vec<BigDecimal> = Vec::new();
+    let mut position = BigDecimal::zero();
+
+    for token in &tokens {
+        if token == BEAT_BREAK_REP {
+            continue;
+        }
+
+        let (id, rest) = token
+            .split_once(':')
+            .ok_or(format!("Missing time in '{}'", token))?;
+
+        let mut parts = rest.split(',');
+        let beats = parse_number(parts.next().unwrap_or(""))?;
+
+        if id != SILENCE_REP {
+            let mut sustain: Option<BigDecimal> = None;
+            let mut args: Vec<(String, BigDecimal)> = Vec::new();
+
+            for arg in parts {
+                let (name, value) = split_arg(arg)?;
+
+                match value.strip_prefix('*') {
+                    // Relative to the reserved time, like legato
+                    Some(factor) if name == SUSTAIN_ARG => sustain = Some(beats.clone() * parse_number(factor)?),
+                    None if name == SUSTAIN_ARG => sustain = Some(parse_number(value)?),
+                    None if name == legato_arg => sustain = Some(beats.clone() * parse_number(value)?),
+                    None => args.push((name.to_string(), parse_number(value)?)),
+                    Some(_) => return Err(format!("Unsupported arg: {}", arg)),
+                }
+            }
+
+            notes.push(PhraseNote {
+                id: id.to_string(),
+                is_sample: !is_control && id.parse::<u32>().is_ok(),
+                at: position.clone(),
+                sustain,
+                args,
+            });
+        }
+
+        onsets.push(position.clone());
+        position += beats;
+    }
+
+    let length = length.filter(|length| *length > position).unwrap_or(position);
+
+    // Synth notes without a sustain arg are held until something else starts
+    for note in notes.iter_mut().filter(|note| note.sustain.is_none() && !note.is_sample && !is_control) {
+        let release = onsets
+            .iter()
+            .find(|onset| **onset > note.at)
+            .unwrap_or(&length);
+        note.sustain = Some(release - note.at.clone());
+    }
+
+    Ok(Phrase { notes, length, sound: None, bus: None })
+}
+
+// "amp0.3" -> ("amp", "0.3")
+fn split_arg(arg: &str) -> Result<(&str, &str), String> {
+    let split = arg
+        .find(|c: char| c.is_ascii_digit() || c == '-' || c == '.' || c == '*')
+        .ok_or(format!("Missing value in arg '{}'", arg))?;
+    Ok(arg.split_at(split))
+}
+
+fn expand(body: &str, definitions: &HashMap<String, String>, depth: usize, tokens: &mut Vec<String>) -> Result<(), String> {
+    if depth > MAX_NESTING {
+        return Err("Definitions nested too deep".to_string());
+    }
+
+    for token in body.split_whitespace() {
+        if token.starts_with('$') {
+            let definition = definitions
+                .get(token)
+                .ok_or(format!("Unknown definition: {}", token))?;
+            expand(definition, definitions, depth + 1, tokens)?;
+        } else {
+            tokens.push(token.to_string());
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_number(text: &str) -> Result<BigDecimal, String> {
+    BigDecimal::from_str(text).map_err(|_| format!("Not a number: '{}'", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn beats(text: &str) -> BigDecimal {
+        BigDecimal::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn verify() {
+        let phrases = parse(
+            "# chords: C\n(c5:0 e5:0,sus0.5 g5:1,amp0.3 . x:1 12:2,legato0.5):len8,tot4.00",
+            "legato",
+        )
+        .unwrap();
+
+        assert_eq!(phrases.len(), 1);
+        let phrase = &phrases[0];
+        assert_eq!(phrase.length, beats("8"));
+
+        let summary: Vec<(&str, BigDecimal, Option<BigDecimal>)> = phrase
+            .notes
+            .iter()
+            .map(|note| (note.id.as_str(), note.at.clone(), note.sustain.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                // Held until the chord is over
+                ("c5", beats("0"), Some(beats("1"))),
+                ("e5", beats("0"), Some(beats("0.5"))),
+                ("g5", beats("0"), Some(beats("1"))),
+                ("12", beats("2"), Some(beats("1"))),
+            ]
+        );
+        assert_eq!(phrase.notes[2].args, vec![("amp".to_string(), beats("0.3"))]);
+        assert!(phrase.notes[3].is_sample);
+
+        // Multiline definitions, with one sequence per lane
+        let lanes = parse("# synth\n$ab = c5:1 e5:1\n$cd = x:2\n$ab $cd\n# pads\n(1:4):len4.0,tot4.00", "legato").unwrap();
+        assert_eq!(lanes.len(), 2);
+        assert_eq!(lanes[0].length, beats("4"));
+        assert_eq!(lanes[0].notes[1].at, beats("1"));
+        assert_eq!(lanes[1].notes[0].sustain, None);
+        assert_eq!(lanes[0].sound, None);
+
+        // Section headers hold for the sequence right after them
+        let sections = parse(
+            "# synth: superpiano amp0.5,ofs0\n# chords: C\n(c5:1):len1,tot1\n(e5:1):len1,tot1\n# pads: drums\n(1:1):len1,tot1",
+            "legato",
+        )
+        .unwrap();
+        assert_eq!(
+            sections[0].sound,
+            Some(PhraseSound {
+                instrument: "superpiano".to_string(),
+                args: vec![("amp".to_string(), beats("0.5")), ("ofs".to_string(), beats("0"))],
+            })
+        );
+        assert_eq!(sections[1].sound, None);
+        assert_eq!(sections[2].sound.as_ref().map(|sound| sound.instrument.as_str()), Some("drums"));
+
+        // Control lanes hold values, not pad hits
+        let lanes = parse("# synth\n(c5:4):len4,tot4\n# bus 2\n(x:1 1:2 0.4:1):len4,tot4", "legato").unwrap();
+        assert_eq!(lanes[0].bus, None);
+        assert_eq!(lanes[1].bus, Some(2));
+        assert_eq!(lanes[1].notes[0].at, beats("1"));
+        assert!(!lanes[1].notes[0].is_sample);
+        assert_eq!(lanes[1].notes[1].sustain, None);
+
+        assert!(parse("$ab $missing", "legato").is_err());
+        assert!(parse("(c5):len4", "legato").is_err());
+        assert!(parse("# nothing", "legato").is_err());
+    }
+}
@@ -1,10 +1,18 @@
 use std::collections::HashMap;
 use std::str::FromStr;
 use std::time::Instant;
-use bigdecimal::BigDecimal;
+use itertools::Itertools;
 use rosc::OscType;
 
 use crate::config::Config;
+use crate::event_history::{
+    stringify_lanes, EventHistory, LoopLength, QuantizeMode, SequenceOptions, StringifyOptions, SustainMode, SustainOutput,
+    ALL_LANES,
+};
+use crate::quantization::Quantization;
+use crate::keyboard_model::pad_id_for_letter;
+use crate::recording::{RecordMode, Recorder};
+use crate::time_signature::TimeSignature;
 
 /*
     Configurable, shared state variables.
@@ -16,6 +24,15 @@ pub enum KeyboardMode {
     Sampler,
 }
 
+impl KeyboardMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyboardMode::Keyboard => "keyboard",
+            KeyboardMode::Sampler => "sampler",
+        }
+    }
+}
+
 pub struct PadsConfiguration {
     pub pads: HashMap<u8, i32>, // <pad_id, sample_index>
     pub pack_name: String,
@@ -42,7 +59,8 @@ impl PadsConfiguration {
 
 pub struct State {
     pub bpm: i64,
-    pub quantization: BigDecimal,
+    pub time_signature: TimeSignature,
+    pub quantization: Quantization,
     pub available_instruments: Vec<String>,
     pub available_packs: Vec<String>,
     pub message_args: Vec<OscType>,
@@ -50,10 +68,21 @@ pub struct State {
     pub last_loop_start_time: Option<Instant>,
     pub pads_configuration: PadsConfiguration,
     pub keyboard_mode: KeyboardMode,
-    pub record_history: bool,
+    pub octave: u8,
+    pub recorder: Recorder,
+    pub metronome_enabled: bool,
     pub quantize_enabled: bool,
+    pub quantize_mode: QuantizeMode,
     pub multiline_output: bool,
+    pub sustain_mode: SustainMode,
+    pub loop_length: LoopLength,
+    // "all" or the name of a single lane to output
+    pub output_lane: String,
+    // Lanes currently in history, kept up to date by the history thread
+    pub lanes: Vec<String>,
     pub history_preview: String,
+    // Counts re-renders of history_preview, so other views of history know when to refresh
+    pub history_revision: u64,
 }
 
 impl State {
@@ -75,7 +104,8 @@ impl State {
 
         State {
             bpm: cfg.bpm,
-            quantization: BigDecimal::from_str(&cfg.quantization).unwrap(),
+            time_signature: TimeSignature::from_name(&cfg.time_signature).unwrap_or(TimeSignature { beats: 4, unit: 4 }),
+            quantization: Quantization::from_config(cfg),
             message_args: msg_args,
             instrument_name: cfg.instrument_name.clone(),
             available_instruments: cfg.available_instruments.clone(),
@@ -83,13 +113,117 @@ impl State {
             last_loop_start_time: None,
             pads_configuration: PadsConfiguration::new_with_pack(&cfg.default_pack),
             keyboard_mode: mode,
-            record_history: cfg.record_history,
+            octave: cfg.initial_octave,
+            recorder: Recorder::new(
+                RecordMode::from_name(&cfg.record_mode).unwrap_or(RecordMode::Always),
+                cfg.record_history,
+                Some(cfg.punch_out_loops).filter(|loops| *loops > 0),
+                cfg.count_in_bars,
+            ),
+            metronome_enabled: cfg.metronome_enabled,
             quantize_enabled: cfg.quantize_enabled,
+            quantize_mode: QuantizeMode::from_name(&cfg.quantize_mode).unwrap_or(QuantizeMode::Relative),
             multiline_output: cfg.multiline_output,
+            sustain_mode: SustainMode::from_name(&cfg.sustain_mode).unwrap_or(SustainMode::Off),
+            loop_length: LoopLength::from_name(&cfg.loop_length, cfg.loop_length_bars)
+                .unwrap_or(LoopLength::PowerOfTwoBars),
+            output_lane: cfg.output_lane.clone(),
+            lanes: Vec::new(),
             history_preview: String::new(),
+            history_revision: 0,
         }
     }
 
+    /*
+        Externally visible state as <field name, values> pairs.
+        Used both to answer state queries and to detect changes worth broadcasting.
+    */
+    pub fn snapshot(&self) -> Vec<(&'static str, Vec<OscType>)> {
+        let pad_map: Vec<OscType> = self.pads_configuration.pads.iter()
+            .sorted_by_key(|(pad_id, _)| **pad_id)
+            .flat_map(|(pad_id, sample_index)| {
+                vec![OscType::Int(*pad_id as i32), OscType::Int(*sample_index)]
+            })
+            .collect();
+
+        vec![
+            ("bpm", vec![OscType::Int(self.bpm as i32)]),
+            ("time_signature", vec![OscType::String(self.time_signature.name())]),
+            ("quantization", vec![
+                OscType::String(self.quantization.step.to_string()),
+                OscType::Int(self.quantization.tuplet as i32),
+                OscType::Int(self.quantization.swing as i32),
+                OscType::Int(self.quantization.strength as i32),
+            ]),
+            ("instrument", vec![OscType::String(self.instrument_name.clone())]),
+            ("args", self.message_args.clone()),
+            ("mode", vec![OscType::String(self.keyboard_mode.name().to_string())]),
+            ("pack", vec![OscType::String(self.pads_configuration.pack_name.clone())]),
+            ("pad_args", self.pads_configuration.args.clone()),
+            ("pad_map", pad_map),
+            ("octave", vec![OscType::Int(self.octave as i32)]),
+            ("recording", vec![OscType::Bool(self.recorder.is_recording())]),
+            ("record_status", vec![OscType::String(self.recorder.status.name().to_string())]),
+            ("record_mode", vec![OscType::String(self.recorder.mode.name().to_string())]),
+            ("punch_out", vec![OscType::Int(self.recorder.punch_out_loops.unwrap_or(0) as i32)]),
+            ("count_in", vec![OscType::Int(self.recorder.count_in_bars as i32)]),
+            ("metronome", vec![OscType::Bool(self.metronome_enabled)]),
+            ("quantize", vec![OscType::Bool(self.quantize_enabled)]),
+            ("quantize_mode", vec![OscType::String(self.quantize_mode.name().to_string())]),
+            ("multiline", vec![OscType::Bool(self.multiline_output)]),
+            ("sustain_mode", vec![OscType::String(self.sustain_mode.name().to_string())]),
+            ("loop_length", match self.loop_length {
+                LoopLength::Bars(bars) => vec![OscType::String(self.loop_length.name().to_string()), OscType::Int(bars as i32)],
+                _ => vec![OscType::String(self.loop_length.name().to_string())],
+            }),
+            ("output_lane", vec![OscType::String(self.output_lane.clone())]),
+            ("lanes", self.lanes.iter().map(|lane| OscType::String(lane.clone())).collect()),
+        ]
+    }
+
+    // Output settings copied out, so history can be rendered after the state lock is released
+    pub fn render_settings(&self) -> RenderSettings {
+        let cfg = Config::get();
+
+        let sequence_options = SequenceOptions {
+            bpm: self.bpm,
+            quantization: if self.quantize_enabled {
+                self.quantization.clone()
+            } else {
+                Quantization::off()
+            },
+            quantize_mode: self.quantize_mode,
+            ..SequenceOptions::from_config(cfg)
+        };
+
+        let configured = StringifyOptions::from_config(cfg);
+        let options = StringifyOptions {
+            multiline: self.multiline_output,
+            sustain: SustainOutput { mode: self.sustain_mode, ..configured.sustain },
+            loop_length: self.loop_length,
+            time_signature: self.time_signature,
+            ..configured
+        };
+
+        RenderSettings {
+            sequence_options,
+            options,
+            output_lane: self.output_lane.clone(),
+            per_instrument: cfg.lanes_per_instrument,
+        }
+    }
+
+    // all -> each lane in history -> all
+    pub fn cycle_output_lane(&mut self) {
+        let position = self.lanes.iter().position(|lane| *lane == self.output_lane);
+
+        self.output_lane = match position {
+            Some(index) if index + 1 < self.lanes.len() => self.lanes[index + 1].clone(),
+            Some(_) => ALL_LANES.to_string(),
+            None => self.lanes.first().cloned().unwrap_or(ALL_LANES.to_string()),
+        };
+    }
+
     pub fn set_args(&mut self, args: Vec<OscType>) {
         self.message_args = args;
     }
@@ -98,7 +232,30 @@ impl State {
         self.bpm = value;
     }
 
-    pub fn set_quantization(&mut self, number_string: &str) {
-        self.quantization = BigDecimal::from_str(number_string).unwrap();
+
+    // Old keyboard style configuration: point the pad behind a letter key at a sample index
+    pub fn set_letter_index(&mut self, letter: char, sample_index: i32) -> bool {
+        match pad_id_for_letter(letter) {
+            Some(pad_id) => {
+                self.pads_configuration.pads.insert(pad_id, sample_index);
+                true
+            }
+            None => false,
+        }
     }
-}
\ No newline at end of file
+}
+
+// Everything needed to stringify history, as taken from State by render_settings
+pub struct RenderSettings {
+    pub sequence_options: SequenceOptions,
+    pub options: StringifyOptions,
+    pub output_lane: String,
+    pub per_instrument: bool,
+}
+
+impl RenderSettings {
+    // Shuttle notation for <history>
+    pub fn render(&self, history: &EventHistory) -> String {
+        stringify_lanes(history, &self.output_lane, self.per_instrument, &self.sequence_options, &self.options)
+    }
+}
@@ -0,0 +1,63 @@
+use bigdecimal::BigDecimal;
+
+/*
+    Time signature, e.g. 6/8. Beats in history and bpm are quarter notes,
+        so a counted beat is 4/<unit> of those.
+*/
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TimeSignature {
+    // Counted beats per bar
+    pub beats: u32,
+    // Note value of a counted beat: 4 for quarters, 8 for eighths
+    pub unit: u32,
+}
+
+impl TimeSignature {
+    pub fn from_name(name: &str) -> Option<TimeSignature> {
+        let (beats, unit) = name.trim().split_once('/')?;
+        let beats: u32 = beats.trim().parse().ok()?;
+        let unit: u32 = unit.trim().parse().ok()?;
+
+        // Units are powers of two, from whole notes to 32nds
+        if beats == 0 || ![1, 2, 4, 8, 16, 32].contains(&unit) {
+            return None;
+        }
+
+        Some(TimeSignature { beats, unit })
+    }
+
+    pub fn name(&self) -> String {
+        format!("{}/{}", self.beats, self.unit)
+    }
+
+    // Length of a counted beat, in quarter note beats
+    pub fn beat_length(&self) -> BigDecimal {
+        BigDecimal::from(4) / BigDecimal::from(self.unit)
+    }
+
+    // Length of a bar, in quarter note beats: 4 in 4/4, 3 in 6/8
+    pub fn bar_length(&self) -> BigDecimal {
+        BigDecimal::from(self.beats) * self.beat_length()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn verify() {
+        let six_eight = TimeSignature::from_name("6/8").unwrap();
+        assert_eq!(six_eight, TimeSignature { beats: 6, unit: 8 });
+        assert_eq!(six_eight.beat_length(), BigDecimal::from_str("0.5").unwrap());
+        assert_eq!(six_eight.name(), "6/8");
+        assert_eq!(six_eight.bar_length(), BigDecimal::from(3));
+
+        assert_eq!(TimeSignature::from_name(" 3 / 4 ").unwrap().beat_length(), BigDecimal::from(1));
+        assert_eq!(TimeSignature::from_name("4/3"), None);
+        assert_eq!(TimeSignature::from_name("0/4"), None);
+        assert_eq!(TimeSignature::from_name("four"), None);
+    }
+}
@@ -1,6 +1,6 @@
-use std::collections::HashMap;
 use std::num::NonZeroU64;
 use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::Duration;
 use bigdecimal::{BigDecimal, FromPrimitive, One, RoundingMode, ToPrimitive};
@@ -46,8 +46,9 @@ pub fn duration_to_beats(duration: Duration, bpm: i64) -> BigDecimal {
     seconds_elapsed / beats_per_second
 }
 
+// E.g. ["amp", 0.5, "ofs", 0] => "amp0.5,ofs0", in the order given; a repeated name keeps its last value
 pub fn shuttlefiy_args(args: Vec<OscType>) -> String {
-    let mut map: HashMap<String, OscType> = HashMap::new();
+    let mut pairs: Vec<(String, OscType)> = Vec::new();
 
     let mut last_key_lol: Option<String> = None;
     let mut expect_key = true;
@@ -62,15 +63,19 @@ pub fn shuttlefiy_args(args: Vec<OscType>) -> String {
             value => {
                 if !expect_key && last_key_lol.clone().is_some() {
                     expect_key = true;
-                    map.insert(last_key_lol.clone().unwrap(), value);
+                    let key = last_key_lol.clone().unwrap();
+                    match pairs.iter_mut().find(|(existing, _)| *existing == key) {
+                        Some(pair) => pair.1 = value,
+                        None => pairs.push((key, value)),
+                    }
                 }
             }
         }
     }
 
-    map.iter()
+    pairs.iter()
         .map(|entry| {
-            let val: String = match entry.1 {
+            let val: String = match &entry.1 {
                 OscType::Int(int) => int.to_string(),
                 OscType::Float(float) => float.to_string(),
                 OscType::String(str) => str.to_string(),
@@ -82,6 +87,13 @@ pub fn shuttlefiy_args(args: Vec<OscType>) -> String {
         .collect::<Vec<String>>().join(",")
 }
 
+// Written before being renamed to <path>: song.bbd -> song.bbd.tmp, so it never clashes with a sibling file
+pub fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -104,5 +116,15 @@ mod tests {
             BigDecimal::from_str("0.25").unwrap(),
         ), BigDecimal::from_str("0.75").unwrap());
 
+        assert_eq!(shuttlefiy_args(vec![
+            OscType::String("amp".to_string()),
+            OscType::Float(0.5),
+            OscType::String("ofs".to_string()),
+            OscType::Int(0),
+            OscType::String("amp".to_string()),
+            OscType::Float(0.25),
+        ]), "amp0.25,ofs0");
+
+        assert_eq!(temporary_path(Path::new("songs/song.bbd")), PathBuf::from("songs/song.bbd.tmp"));
     }
 }
\ No newline at end of file